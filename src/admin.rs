@@ -2,7 +2,10 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+use crate::breakdown::{Breakdown, RequestStats};
+use crate::config_file::Routing;
 use crate::metrics::*;
+use crate::trace::Trace;
 use crate::Arc;
 use crate::Config;
 use rustcommon_heatmap::AtomicHeatmap;
@@ -10,58 +13,47 @@ use rustcommon_heatmap::AtomicU64;
 use rustcommon_logger::Drain;
 use rustcommon_ratelimiter::Ratelimiter;
 use rustcommon_waterfall::WaterfallBuilder;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::Instant;
 
-use std::net::SocketAddr;
-use std::time::Duration;
 use tiny_http::{Method, Response, Server};
 
+/// An endpoint is reported as an outlier if its p99 latency is greater than
+/// the median p99 of all endpoints by this factor.
+const OUTLIER_THRESHOLD: f64 = 2.0;
+
 pub struct Admin {
-    config: Option<Arc<Config>>,
+    config: Arc<Config>,
     snapshot: Snapshot,
     connect_heatmap: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
     reconnect_ratelimit: Option<Arc<Ratelimiter>>,
     request_heatmap: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
     request_ratelimit: Option<Arc<Ratelimiter>>,
     request_waterfall: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
+    breakdown: Option<Arc<Breakdown>>,
+    trace: Option<Arc<Trace>>,
     server: Option<Server>,
     log: Box<dyn Drain>,
 }
 
 impl Admin {
     pub fn new(config: Arc<Config>, log: Box<dyn Drain>) -> Self {
-        let snapshot = Snapshot::new(None, None);
+        let snapshot = Snapshot::new(None, None, None);
         let server = config
             .general()
             .admin()
             .map(|admin_addr| Server::http(admin_addr).unwrap());
 
         Self {
-            config: Some(config),
-            snapshot,
-            connect_heatmap: None,
-            reconnect_ratelimit: None,
-            request_heatmap: None,
-            request_ratelimit: None,
-            request_waterfall: None,
-            server,
-            log,
-        }
-    }
-
-    pub fn for_replay(admin_addr: Option<SocketAddr>, log: Box<dyn Drain>) -> Self {
-        let snapshot = Snapshot::new(None, None);
-        let server = admin_addr.map(|admin_addr| Server::http(admin_addr).unwrap());
-
-        Self {
-            config: None,
+            config,
             snapshot,
             connect_heatmap: None,
             reconnect_ratelimit: None,
             request_heatmap: None,
             request_ratelimit: None,
             request_waterfall: None,
+            breakdown: None,
+            trace: None,
             server,
             log,
         }
@@ -87,21 +79,31 @@ impl Admin {
         self.request_waterfall = heatmap;
     }
 
+    pub fn set_breakdown(&mut self, breakdown: Option<Arc<Breakdown>>) {
+        self.breakdown = breakdown;
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Arc<Trace>>) {
+        self.trace = trace;
+    }
+
     pub fn run(mut self) {
-        let mut next = Instant::now()
-            + match self.config.as_ref() {
-                Some(config) => config.general().interval(),
-                None => Duration::from_secs(60),
-            };
-        let mut snapshot =
-            Snapshot::new(self.connect_heatmap.as_ref(), self.request_heatmap.as_ref());
+        let mut next = Instant::now() + self.config.general().interval();
+        let mut snapshot = Snapshot::new(
+            self.connect_heatmap.as_ref(),
+            self.request_heatmap.as_ref(),
+            self.breakdown.as_ref(),
+        );
 
         loop {
             while Instant::now() < next {
                 rustcommon_time::refresh_clock();
                 let _ = self.log.flush();
-                snapshot =
-                    Snapshot::new(self.connect_heatmap.as_ref(), self.request_heatmap.as_ref());
+                snapshot = Snapshot::new(
+                    self.connect_heatmap.as_ref(),
+                    self.request_heatmap.as_ref(),
+                    self.breakdown.as_ref(),
+                );
                 if let Some(ref server) = self.server {
                     while let Ok(Some(mut request)) = server.try_recv() {
                         let url = request.url();
@@ -182,21 +184,19 @@ impl Admin {
                 }
                 std::thread::sleep(std::time::Duration::from_millis(10));
             }
-            next += match self.config.as_ref() {
-                Some(config) => config.general().interval(),
-                None => Duration::from_secs(60),
-            };
+            next += self.config.general().interval();
 
             let window = WINDOW.value();
 
             info!("-----");
             info!("Window: {}", window);
             info!(
-                "Connections: Attempts: {} Opened: {} Errors: {} Timeouts: {} Open: {}",
+                "Connections: Attempts: {} Opened: {} Errors: {} Timeouts: {} Setup Errors: {} Open: {}",
                 snapshot.delta_count(&self.snapshot, CONNECT.name()),
                 snapshot.delta_count(&self.snapshot, SESSION.name()),
                 snapshot.delta_count(&self.snapshot, CONNECT_EX.name()),
                 snapshot.delta_count(&self.snapshot, CONNECT_TIMEOUT.name()),
+                snapshot.delta_count(&self.snapshot, CONNECT_SETUP_EX.name()),
                 OPEN.value()
             );
 
@@ -221,11 +221,65 @@ impl Admin {
                 request_success, response_success, connect_success
             );
 
+            info!(
+                "Timeouts: Request: {}",
+                snapshot.delta_count(&self.snapshot, REQUEST_TIMEOUT.name())
+            );
+
             let hit_rate =
                 snapshot.hitrate(&self.snapshot, REQUEST_GET.name(), RESPONSE_HIT.name());
 
             info!("Hit-rate: {:.2} %", hit_rate);
 
+            info!(
+                "Responses: Miss: {} Stored: {} Not Stored: {}",
+                snapshot.delta_count(&self.snapshot, RESPONSE_MISS.name()),
+                snapshot.delta_count(&self.snapshot, RESPONSE_STORED.name()),
+                snapshot.delta_count(&self.snapshot, RESPONSE_NOT_STORED.name())
+            );
+
+            if self.config.request().open_loop() {
+                info!(
+                    "Open Loop: Delayed: {} Dropped: {}",
+                    snapshot.delta_count(&self.snapshot, REQUEST_DELAYED.name()),
+                    snapshot.delta_count(&self.snapshot, REQUEST_DROPPED.name())
+                );
+            }
+            // only trace replay schedules requests which can be late
+            let late = snapshot.delta_count(&self.snapshot, REPLAY_LATE.name());
+            if late > 0 {
+                info!(
+                    "Replay: Late: {} Behind: {} us",
+                    late,
+                    REPLAY_BEHIND.value()
+                );
+            }
+            if self.config.request().verify() {
+                info!(
+                    "Verify: Checked: {} Corrupt: {} Truncated: {} Stale: {} Phantom: {} Lost: {}",
+                    snapshot.delta_count(&self.snapshot, VERIFY.name()),
+                    snapshot.delta_count(&self.snapshot, VERIFY_CORRUPT.name()),
+                    snapshot.delta_count(&self.snapshot, VERIFY_TRUNCATED.name()),
+                    snapshot.delta_count(&self.snapshot, VERIFY_STALE.name()),
+                    snapshot.delta_count(&self.snapshot, VERIFY_PHANTOM.name()),
+                    snapshot.delta_count(&self.snapshot, VERIFY_LOST.name()),
+                );
+            }
+            if self.config.routing() == Some(Routing::RedisCluster) {
+                info!(
+                    "Cluster: Refreshes: {} Moved: {} Ask: {}",
+                    snapshot.delta_count(&self.snapshot, CLUSTER_REFRESH.name()),
+                    snapshot.delta_count(&self.snapshot, CLUSTER_MOVED.name()),
+                    snapshot.delta_count(&self.snapshot, CLUSTER_ASK.name()),
+                );
+            }
+            if self.config.routing().is_some() {
+                info!(
+                    "Routing: Unrouted: {}",
+                    snapshot.delta_count(&self.snapshot, REQUEST_UNROUTED.name())
+                );
+            }
+
             if let Some(ref heatmap) = self.connect_heatmap {
                 let p25 = heatmap.percentile(25.0).unwrap_or(0);
                 let p50 = heatmap.percentile(50.0).unwrap_or(0);
@@ -252,21 +306,66 @@ impl Admin {
                 );
             }
 
+            // endpoints are only reported individually if they are outliers
+            // as there may be a large number of them
+            for entry in snapshot.breakdown.iter().filter(|e| e.label != "endpoint") {
+                let previous = self
+                    .snapshot
+                    .breakdown
+                    .iter()
+                    .find(|e| e.label == entry.label && e.value == entry.value);
+                let time = (snapshot.timestamp - self.snapshot.timestamp).as_secs_f64();
+                let rate = |name: &str| {
+                    (entry.counter(name) - previous.map(|e| e.counter(name)).unwrap_or(0)) as f64
+                        / time
+                };
+                let latency = if entry.percentiles.is_empty() {
+                    String::new()
+                } else {
+                    format!(
+                        " Latency (us): p50: {} p90: {} p99: {} p999: {} p9999: {}",
+                        entry.percentile("p50"),
+                        entry.percentile("p90"),
+                        entry.percentile("p99"),
+                        entry.percentile("p999"),
+                        entry.percentile("p9999"),
+                    )
+                };
+                info!(
+                    "Breakdown: {}: {} Request: {:.2} rps Response: {:.2} rps Errors: {:.2} rps Timeouts: {:.2} rps{}",
+                    entry.label,
+                    entry.value,
+                    rate("request"),
+                    rate("response"),
+                    rate("response_ex"),
+                    rate("request_timeout"),
+                    latency,
+                );
+            }
+
+            let p99s: Vec<(String, u64)> = snapshot
+                .breakdown
+                .iter()
+                .filter(|e| e.label == "endpoint")
+                .map(|e| (e.value.clone(), e.percentile("p99")))
+                .collect();
+            let (outliers, median) = find_outliers(&p99s);
+            for (endpoint, p99) in outliers {
+                info!(
+                    "Outlier: Endpoint: {} p99: {} us Median p99: {} us",
+                    endpoint, p99, median
+                );
+            }
+
             WINDOW.increment();
             self.snapshot = snapshot.clone();
 
-            if let Some(max_window) = self
-                .config
-                .as_ref()
-                .and_then(|config| config.general().windows())
-            {
+            if let Some(max_window) = self.config.general().windows() {
                 if window >= max_window as u64 {
                     if let Some(ref heatmap) = self.request_waterfall {
-                        if let Some(file) = self.config.as_ref().and_then(|c| c.waterfall().file())
-                        {
-                            let config = self.config.as_ref().unwrap();
-                            let scale = config.waterfall().scale();
-                            let palette = config.waterfall().palette();
+                        if let Some(file) = self.config.waterfall().file() {
+                            let scale = self.config.waterfall().scale();
+                            let palette = self.config.waterfall().palette();
 
                             WaterfallBuilder::new(&file)
                                 .label(100, "100ns")
@@ -281,6 +380,9 @@ impl Admin {
                                 .build(&heatmap.load());
                         }
                     }
+                    if let Some(ref trace) = self.trace {
+                        trace.finish();
+                    }
                     break;
                 }
             }
@@ -295,6 +397,81 @@ pub struct Snapshot {
     timestamp: Instant,
     connect_percentiles: Vec<(String, u64)>,
     request_percentiles: Vec<(String, u64)>,
+    breakdown: Vec<BreakdownEntry>,
+}
+
+/// The metrics for a single verb, keyspace or endpoint.
+#[derive(Clone)]
+pub struct BreakdownEntry {
+    /// the name of the label, one of `verb`, `keyspace` or `endpoint`
+    label: &'static str,
+    value: String,
+    /// the counters paired with the metric names they are exposed as
+    counters: Vec<(&'static str, u64)>,
+    percentiles: Vec<(String, u64)>,
+}
+
+impl BreakdownEntry {
+    fn new(
+        label: &'static str,
+        value: String,
+        stats: &RequestStats,
+        percentiles: &[(&str, f64)],
+    ) -> Self {
+        Self {
+            label,
+            value,
+            counters: vec![
+                ("request", stats.request()),
+                ("response", stats.response()),
+                ("response_ex", stats.response_ex()),
+                ("request_timeout", stats.timeout()),
+            ],
+            percentiles: stats
+                .heatmap()
+                .map(|heatmap| {
+                    percentiles
+                        .iter()
+                        .map(|(l, v)| (l.to_string(), heatmap.percentile(*v).unwrap_or(0)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    fn counter(&self, name: &str) -> u64 {
+        self.counters
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, v)| *v)
+            .unwrap_or(0)
+    }
+
+    fn percentile(&self, label: &str) -> u64 {
+        self.percentiles
+            .iter()
+            .find(|(l, _)| l == label)
+            .map(|(_, v)| *v)
+            .unwrap_or(0)
+    }
+}
+
+/// Returns the endpoints with a p99 latency which is more than
+/// `OUTLIER_THRESHOLD` times the median p99 across all endpoints, along with
+/// the median. Endpoints without any latency samples are not considered.
+fn find_outliers(p99s: &[(String, u64)]) -> (Vec<(String, u64)>, u64) {
+    let mut sorted: Vec<u64> = p99s.iter().map(|(_, v)| *v).filter(|v| *v > 0).collect();
+    if sorted.len() < 2 {
+        return (Vec::new(), 0);
+    }
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2];
+    let outliers = p99s
+        .iter()
+        .filter(|(_, v)| *v as f64 > median as f64 * OUTLIER_THRESHOLD)
+        .cloned()
+        .collect();
+    (outliers, median)
 }
 
 #[derive(Clone)]
@@ -307,6 +484,7 @@ impl Snapshot {
     fn new(
         connect_heatmap: Option<&Arc<AtomicHeatmap<u64, AtomicU64>>>,
         request_heatmap: Option<&Arc<AtomicHeatmap<u64, AtomicU64>>>,
+        breakdown: Option<&Arc<Breakdown>>,
     ) -> Self {
         let mut counters = HashMap::new();
         let mut gauges = HashMap::new();
@@ -357,12 +535,47 @@ impl Snapshot {
             }
         }
 
+        let mut entries = Vec::new();
+        if let Some(breakdown) = breakdown {
+            for (verb, stats) in breakdown.verbs() {
+                entries.push(BreakdownEntry::new(
+                    "verb",
+                    verb.as_ref().to_string(),
+                    stats,
+                    &percentiles,
+                ));
+            }
+            for (index, stats) in breakdown.keyspaces() {
+                entries.push(BreakdownEntry::new(
+                    "keyspace",
+                    index.to_string(),
+                    stats,
+                    &percentiles,
+                ));
+            }
+            for (endpoint, stats) in breakdown.endpoints() {
+                let mut entry = BreakdownEntry::new(
+                    "endpoint",
+                    endpoint.to_string(),
+                    stats.requests(),
+                    &percentiles,
+                );
+                entry.counters.extend_from_slice(&[
+                    ("connect", stats.connect()),
+                    ("connect_ex", stats.connect_ex()),
+                    ("connect_timeout", stats.connect_timeout()),
+                ]);
+                entries.push(entry);
+            }
+        }
+
         Self {
             counters,
             gauges,
             timestamp: Instant::now(),
             connect_percentiles,
             request_percentiles,
+            breakdown: entries,
         }
     }
 
@@ -412,6 +625,20 @@ impl Snapshot {
         for (label, entry) in &self.request_percentiles {
             data.push(format!("response_latency/{}: {}", label, entry));
         }
+        for entry in &self.breakdown {
+            for (name, value) in &entry.counters {
+                data.push(format!(
+                    "{}/{}/{}: {}",
+                    entry.label, entry.value, name, value
+                ));
+            }
+            for (label, value) in &entry.percentiles {
+                data.push(format!(
+                    "{}/{}/response_latency/{}: {}",
+                    entry.label, entry.value, label, value
+                ));
+            }
+        }
         data.sort();
         let mut content = data.join("\n");
         content += "\n";
@@ -434,6 +661,20 @@ impl Snapshot {
         for (label, entry) in &self.request_percentiles {
             data.push(format!("\"response_latency/{}\": {}", label, entry));
         }
+        for entry in &self.breakdown {
+            for (name, value) in &entry.counters {
+                data.push(format!(
+                    "\"{}/{}/{}\": {}",
+                    entry.label, entry.value, name, value
+                ));
+            }
+            for (label, value) in &entry.percentiles {
+                data.push(format!(
+                    "\"{}/{}/response_latency/{}\": {}",
+                    entry.label, entry.value, label, value
+                ));
+            }
+        }
         data.sort();
         let body = data.join(",");
         let mut content = head;
@@ -481,6 +722,44 @@ impl Snapshot {
                 label, label, percentile, entry
             ));
         }
+        // the breakdown is exported in families which are prefixed by the
+        // label, eg: `verb_request{verb="get"}`, so that they are distinct
+        // from the totals
+        let mut families: BTreeMap<String, (&str, Vec<String>)> = BTreeMap::new();
+        for entry in &self.breakdown {
+            for (name, value) in &entry.counters {
+                let family = format!("{}_{}", entry.label, name);
+                let sample = format!(
+                    "{}{{{}=\"{}\"}} {}",
+                    family, entry.label, entry.value, value
+                );
+                families
+                    .entry(family)
+                    .or_insert(("counter", Vec::new()))
+                    .1
+                    .push(sample);
+            }
+            for (percentile, value) in &entry.percentiles {
+                let family = format!("{}_response_latency", entry.label);
+                let sample = format!(
+                    "{}{{{}=\"{}\",percentile=\"{}\"}} {}",
+                    family, entry.label, entry.value, percentile, value
+                );
+                families
+                    .entry(family)
+                    .or_insert(("gauge", Vec::new()))
+                    .1
+                    .push(sample);
+            }
+        }
+        for (family, (kind, samples)) in families {
+            data.push(format!(
+                "# TYPE {} {}\n{}",
+                family,
+                kind,
+                samples.join("\n")
+            ));
+        }
         data.sort();
         let mut content = data.join("\n");
         content += "\n";
@@ -488,3 +767,55 @@ impl Snapshot {
         parts.join("_")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prometheus_breakdown() {
+        let entry = |value: &str| BreakdownEntry {
+            label: "verb",
+            value: value.to_string(),
+            counters: vec![("request", 1)],
+            percentiles: vec![("p99".to_string(), 100)],
+        };
+        let snapshot = Snapshot {
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            timestamp: Instant::now(),
+            connect_percentiles: Vec::new(),
+            request_percentiles: Vec::new(),
+            breakdown: vec![entry("get"), entry("set")],
+        };
+        let content = snapshot.prometheus();
+        assert_eq!(content.matches("# TYPE verb_request counter").count(), 1);
+        assert!(content.contains("verb_request{verb=\"get\"} 1"));
+        assert!(content.contains("verb_request{verb=\"set\"} 1"));
+        assert_eq!(
+            content
+                .matches("# TYPE verb_response_latency gauge")
+                .count(),
+            1
+        );
+        assert!(!content.contains("\nrequest{"));
+    }
+
+    #[test]
+    fn outlier() {
+        let p99s: Vec<(String, u64)> = vec![
+            ("a".to_string(), 1000),
+            ("b".to_string(), 1100),
+            ("c".to_string(), 900),
+            ("d".to_string(), 5000),
+            ("e".to_string(), 0),
+        ];
+        let (outliers, median) = find_outliers(&p99s);
+        assert_eq!(median, 1100);
+        assert_eq!(outliers, vec![("d".to_string(), 5000)]);
+
+        // a single endpoint can not be an outlier
+        let (outliers, _) = find_outliers(&p99s[0..1]);
+        assert!(outliers.is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Request metrics broken down by verb, keyspace and endpoint. These depend on
+//! the config, so unlike the static metrics they are created at runtime and
+//! shared between the workers and the admin thread.
+
+use crate::config_file::{Protocol, Verb};
+use crate::metrics::Counter;
+use crate::*;
+use rustcommon_heatmap::{AtomicHeatmap, AtomicU64};
+use std::net::SocketAddr;
+
+/// Counters and, optionally, a response latency heatmap for a subset of the
+/// requests. The heatmap has the same resolution as the global response
+/// latency heatmap, which is a few megabytes per heatmap.
+pub struct RequestStats {
+    request: Counter,
+    response: Counter,
+    response_ex: Counter,
+    hit: Counter,
+    miss: Counter,
+    timeout: Counter,
+    heatmap: Option<AtomicHeatmap<u64, AtomicU64>>,
+}
+
+impl RequestStats {
+    fn new(config: &Config, latency: bool) -> Self {
+        Self {
+            request: Counter::new(),
+            response: Counter::new(),
+            response_ex: Counter::new(),
+            hit: Counter::new(),
+            miss: Counter::new(),
+            timeout: Counter::new(),
+            heatmap: if latency {
+                Some(AtomicHeatmap::<u64, AtomicU64>::new(
+                    1_000_000,
+                    3,
+                    Duration::from_secs(config.general().interval().as_secs()),
+                    Duration::from_millis(1000),
+                ))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn request(&self) -> u64 {
+        self.request.value()
+    }
+
+    pub fn response(&self) -> u64 {
+        self.response.value()
+    }
+
+    pub fn response_ex(&self) -> u64 {
+        self.response_ex.value()
+    }
+
+    /// The number of responses which were hits. Unlike the `response_hit`
+    /// metric, a response with several keys is counted once.
+    pub fn hit(&self) -> u64 {
+        self.hit.value()
+    }
+
+    pub fn miss(&self) -> u64 {
+        self.miss.value()
+    }
+
+    pub fn timeout(&self) -> u64 {
+        self.timeout.value()
+    }
+
+    pub fn heatmap(&self) -> Option<&AtomicHeatmap<u64, AtomicU64>> {
+        self.heatmap.as_ref()
+    }
+}
+
+/// The request and connection metrics for a single endpoint. The latency is
+/// always recorded, as it is needed to report outliers.
+pub struct EndpointStats {
+    connect: Counter,
+    connect_ex: Counter,
+    connect_timeout: Counter,
+    requests: RequestStats,
+}
+
+impl EndpointStats {
+    fn new(config: &Config) -> Self {
+        Self {
+            connect: Counter::new(),
+            connect_ex: Counter::new(),
+            connect_timeout: Counter::new(),
+            requests: RequestStats::new(config, true),
+        }
+    }
+
+    pub fn connect(&self) -> u64 {
+        self.connect.value()
+    }
+
+    pub fn connect_ex(&self) -> u64 {
+        self.connect_ex.value()
+    }
+
+    pub fn connect_timeout(&self) -> u64 {
+        self.connect_timeout.value()
+    }
+
+    pub fn requests(&self) -> &RequestStats {
+        &self.requests
+    }
+}
+
+/// The request metrics for each verb, keyspace and endpoint.
+pub struct Breakdown {
+    verbs: Vec<(Verb, RequestStats)>,
+    keyspaces: Vec<RequestStats>,
+    endpoints: Vec<(SocketAddr, EndpointStats)>,
+}
+
+impl Breakdown {
+    pub fn new(config: &Config) -> Self {
+        let configured: Vec<Verb> = match config.general().protocol() {
+            Protocol::Ping => vec![Verb::Ping],
+            Protocol::Echo => vec![Verb::Echo],
+            // requests replayed from a trace are accounted as these verbs
+            _ if config.keyspaces().is_empty() => vec![Verb::Get, Verb::Set, Verb::Delete],
+            _ => config
+                .keyspaces()
+                .iter()
+                .flat_map(|keyspace| keyspace.commands().iter().map(|c| c.verb()))
+                .collect(),
+        };
+        // the latency of each verb and keyspace is only recorded if enabled
+        let latency = config.general().latency_breakdown();
+
+        let mut verbs = Vec::new();
+        for verb in configured {
+            if !verbs.contains(&verb) {
+                verbs.push(verb);
+            }
+        }
+
+        Self {
+            verbs: verbs
+                .into_iter()
+                .map(|verb| (verb, RequestStats::new(config, latency)))
+                .collect(),
+            keyspaces: config
+                .keyspaces()
+                .iter()
+                .map(|_| RequestStats::new(config, latency))
+                .collect(),
+            endpoints: config
+                .endpoints()
+                .into_iter()
+                .map(|endpoint| (endpoint, EndpointStats::new(config)))
+                .collect(),
+        }
+    }
+
+    /// Returns the metrics for each verb.
+    pub fn verbs(&self) -> impl Iterator<Item = (Verb, &RequestStats)> {
+        self.verbs.iter().map(|(verb, stats)| (*verb, stats))
+    }
+
+    /// Returns the metrics for each keyspace, in the order they are configured.
+    pub fn keyspaces(&self) -> impl Iterator<Item = (usize, &RequestStats)> {
+        self.keyspaces.iter().enumerate()
+    }
+
+    /// Returns the metrics for each configured endpoint. Nodes which are
+    /// discovered at runtime, such as in a redis cluster, are not included.
+    pub fn endpoints(&self) -> impl Iterator<Item = (SocketAddr, &EndpointStats)> {
+        self.endpoints
+            .iter()
+            .map(|(endpoint, stats)| (*endpoint, stats))
+    }
+
+    fn endpoint(&self, endpoint: Option<SocketAddr>) -> Option<&EndpointStats> {
+        let endpoint = endpoint?;
+        self.endpoints
+            .iter()
+            .find(|(e, _)| *e == endpoint)
+            .map(|(_, stats)| stats)
+    }
+
+    /// Returns the metrics which apply to a request sent to the endpoint.
+    fn matching(
+        &self,
+        request: &InFlight,
+        endpoint: Option<SocketAddr>,
+    ) -> impl Iterator<Item = &RequestStats> {
+        let verb = self
+            .verbs
+            .iter()
+            .find(|(verb, _)| Some(*verb) == request.verb)
+            .map(|(_, stats)| stats);
+        let keyspace = request.keyspace.and_then(|k| self.keyspaces.get(k));
+        let endpoint = self.endpoint(endpoint).map(|stats| &stats.requests);
+        verb.into_iter().chain(keyspace).chain(endpoint)
+    }
+
+    pub fn connect(&self, endpoint: Option<SocketAddr>) {
+        if let Some(stats) = self.endpoint(endpoint) {
+            stats.connect.increment();
+        }
+    }
+
+    pub fn connect_ex(&self, endpoint: Option<SocketAddr>) {
+        if let Some(stats) = self.endpoint(endpoint) {
+            stats.connect_ex.increment();
+        }
+    }
+
+    pub fn connect_timeout(&self, endpoint: Option<SocketAddr>) {
+        if let Some(stats) = self.endpoint(endpoint) {
+            stats.connect_timeout.increment();
+        }
+    }
+
+    pub fn request(&self, request: &InFlight, endpoint: Option<SocketAddr>) {
+        for stats in self.matching(request, endpoint) {
+            stats.request.increment();
+        }
+    }
+
+    /// Records a response along with its latency in microseconds.
+    pub fn response(
+        &self,
+        request: &InFlight,
+        endpoint: Option<SocketAddr>,
+        now: Instant,
+        latency: u64,
+    ) {
+        for stats in self.matching(request, endpoint) {
+            stats.response.increment();
+            if let Some(ref heatmap) = stats.heatmap {
+                heatmap.increment(now, latency, 1);
+            }
+        }
+    }
+
+    pub fn response_ex(&self, request: &InFlight, endpoint: Option<SocketAddr>) {
+        for stats in self.matching(request, endpoint) {
+            stats.response_ex.increment();
+        }
+    }
+
+    pub fn hit(&self, request: &InFlight, endpoint: Option<SocketAddr>) {
+        for stats in self.matching(request, endpoint) {
+            stats.hit.increment();
+        }
+    }
+
+    pub fn miss(&self, request: &InFlight, endpoint: Option<SocketAddr>) {
+        for stats in self.matching(request, endpoint) {
+            stats.miss.increment();
+        }
+    }
+
+    /// Records a request which timed out. If there is a penalty, it is
+    /// recorded as the latency in microseconds.
+    pub fn timeout(
+        &self,
+        request: &InFlight,
+        endpoint: Option<SocketAddr>,
+        now: Instant,
+        penalty: Option<u64>,
+    ) {
+        for stats in self.matching(request, endpoint) {
+            stats.timeout.increment();
+            if let (Some(heatmap), Some(penalty)) = (&stats.heatmap, penalty) {
+                heatmap.increment(now, penalty, 1);
+            }
+        }
+    }
+}
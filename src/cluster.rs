@@ -0,0 +1,334 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Support for redis cluster. Each key maps onto one of 16384 hash slots and
+//! each slot is served by a single primary. The slot ownership is discovered
+//! by sending `CLUSTER SLOTS` to any node and is refreshed whenever a request
+//! is redirected with `MOVED` or its slot has no known owner. Node addresses
+//! must be IP addresses, as hostnames would need a blocking lookup in the
+//! event loop.
+
+use crate::codec::SlotRange;
+use crate::session::InFlight;
+use crc::{Crc, CRC_16_XMODEM};
+use mio::Token;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+
+/// the number of hash slots in a redis cluster
+const SLOTS: usize = 16384;
+
+/// the checksum used to map keys onto hash slots
+const CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
+
+/// `CLUSTER SLOTS` encoded as a RESP array
+pub const SLOTS_REQUEST: &[u8] = b"*2\r\n$7\r\nCLUSTER\r\n$5\r\nSLOTS\r\n";
+
+/// `ASKING` encoded as a RESP array
+pub const ASKING_REQUEST: &[u8] = b"*1\r\n$6\r\nASKING\r\n";
+
+/// Returns the hash slot for the key. If the key contains a hash tag, which is
+/// a non-empty substring between the first `{` and the next `}`, only the hash
+/// tag is hashed.
+pub fn hash_slot(key: &[u8]) -> u16 {
+    let mut key = key;
+    if let Some(open) = key.iter().position(|b| *b == b'{') {
+        if let Some(close) = key[(open + 1)..].iter().position(|b| *b == b'}') {
+            if close > 0 {
+                key = &key[(open + 1)..(open + 1 + close)];
+            }
+        }
+    }
+    CRC.checksum(key) % SLOTS as u16
+}
+
+/// A request which was redirected and must be sent to another node.
+pub struct Retry {
+    pub endpoint: SocketAddr,
+    pub request: Vec<u8>,
+    /// indicates that the request must be preceded by `ASKING`
+    pub asking: bool,
+    /// the original request record, so that latency includes the redirect
+    pub inflight: Option<InFlight>,
+}
+
+/// Tracks the slot ownership of a redis cluster for a single worker, along
+/// with copies of the requests in flight so that they can be sent again if
+/// they are redirected.
+pub struct Cluster {
+    slots: Vec<Option<SocketAddr>>,
+    seeds: Vec<SocketAddr>,
+    nodes: HashSet<SocketAddr>,
+    added: Vec<SocketAddr>,
+    refresh: bool,
+    refreshing: Option<Token>,
+    inflight: HashMap<Token, VecDeque<Vec<u8>>>,
+    retries: VecDeque<Retry>,
+}
+
+impl Cluster {
+    /// Create a new `Cluster` from the seed nodes. Requests are sent to the
+    /// seed nodes until the first topology refresh has completed.
+    pub fn new(seeds: Vec<SocketAddr>) -> Self {
+        Self {
+            slots: vec![None; SLOTS],
+            nodes: seeds.iter().copied().collect(),
+            seeds,
+            added: Vec::new(),
+            refresh: true,
+            refreshing: None,
+            inflight: HashMap::new(),
+            retries: VecDeque::new(),
+        }
+    }
+
+    /// Returns the node which serves the hash slot for the key. If the slot
+    /// has no known owner, a refresh of the topology is scheduled and one of
+    /// the seed nodes is returned, which redirects the request with `MOVED`.
+    pub fn route(&mut self, key: &[u8]) -> Option<SocketAddr> {
+        let slot = hash_slot(key) as usize;
+        if self.slots[slot].is_none() {
+            self.refresh = true;
+            if self.seeds.is_empty() {
+                return None;
+            }
+            return Some(self.seeds[slot % self.seeds.len()]);
+        }
+        self.slots[slot]
+    }
+
+    /// Returns true if the topology should be refreshed and there is no
+    /// refresh in progress.
+    pub fn needs_refresh(&self) -> bool {
+        self.refresh && self.refreshing.is_none()
+    }
+
+    /// Marks the session as waiting for the reply to `CLUSTER SLOTS`.
+    pub fn start_refresh(&mut self, token: Token) {
+        self.refreshing = Some(token);
+    }
+
+    /// Returns true if the session is waiting for the reply to
+    /// `CLUSTER SLOTS`.
+    pub fn is_refreshing(&self, token: Token) -> bool {
+        self.refreshing == Some(token)
+    }
+
+    /// Returns the nodes which have been discovered since this was last
+    /// called, so that sessions can be opened to them.
+    pub fn take_added(&mut self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.added)
+    }
+
+    fn add_node(&mut self, node: SocketAddr) {
+        if self.nodes.insert(node) {
+            self.added.push(node);
+        }
+    }
+
+    /// Replaces the slot ownership with the ranges from `CLUSTER SLOTS`. The
+    /// address of the node which sent the reply is used for ranges which have
+    /// an empty host.
+    pub fn update(&mut self, ranges: Vec<SlotRange>, endpoint: SocketAddr) {
+        self.refresh = false;
+        self.refreshing = None;
+
+        let mut slots = vec![None; SLOTS];
+        for range in ranges {
+            let node = match resolve(&range.host, range.port, endpoint.ip()) {
+                Some(node) => node,
+                None => {
+                    warn!(
+                        "cluster node address is not an ip address: {}",
+                        String::from_utf8_lossy(&range.host)
+                    );
+                    continue;
+                }
+            };
+            for slot in range.start..=range.end.min(SLOTS as u16 - 1) {
+                slots[slot as usize] = Some(node);
+            }
+            self.add_node(node);
+        }
+        self.slots = slots;
+    }
+
+    /// Keeps a copy of a request which was written to the session.
+    pub fn sent(&mut self, token: Token, request: &[u8]) {
+        self.inflight
+            .entry(token)
+            .or_default()
+            .push_back(request.to_vec());
+    }
+
+    /// Discards the oldest request on the session once it has a response.
+    pub fn received(&mut self, token: Token) {
+        if let Some(requests) = self.inflight.get_mut(&token) {
+            requests.pop_front();
+        }
+    }
+
+    /// Handles a `MOVED` redirect for the oldest request on the session. The
+    /// slot is assigned to the new node immediately and a refresh of the
+    /// topology is scheduled.
+    pub fn moved(
+        &mut self,
+        token: Token,
+        slot: u16,
+        endpoint: SocketAddr,
+        inflight: Option<InFlight>,
+    ) {
+        if let Some(owner) = self.slots.get_mut(slot as usize) {
+            *owner = Some(endpoint);
+        }
+        self.refresh = true;
+        self.redirect(token, endpoint, false, inflight)
+    }
+
+    /// Handles an `ASK` redirect for the oldest request on the session. The
+    /// slot ownership is unchanged.
+    pub fn ask(&mut self, token: Token, endpoint: SocketAddr, inflight: Option<InFlight>) {
+        self.redirect(token, endpoint, true, inflight)
+    }
+
+    fn redirect(
+        &mut self,
+        token: Token,
+        endpoint: SocketAddr,
+        asking: bool,
+        inflight: Option<InFlight>,
+    ) {
+        if let Some(request) = self.inflight.get_mut(&token).and_then(|r| r.pop_front()) {
+            self.retries.push_back(Retry {
+                endpoint,
+                request,
+                asking,
+                inflight,
+            });
+        }
+        self.add_node(endpoint);
+    }
+
+    /// Returns the node for the next request which must be sent again.
+    pub fn next_retry(&self) -> Option<SocketAddr> {
+        self.retries.front().map(|retry| retry.endpoint)
+    }
+
+    pub fn pop_retry(&mut self) -> Option<Retry> {
+        self.retries.pop_front()
+    }
+
+    /// Discards the requests in flight for a session which has been closed.
+    pub fn closed(&mut self, token: Token) {
+        self.inflight.remove(&token);
+        if self.refreshing == Some(token) {
+            self.refreshing = None;
+        }
+    }
+}
+
+/// Returns the address of a node. An empty host indicates the node is
+/// reachable at the same address as the node which sent the topology. Hosts
+/// which are not IP addresses are not resolved.
+fn resolve(host: &[u8], port: u16, default: IpAddr) -> Option<SocketAddr> {
+    let host = std::str::from_utf8(host).ok()?;
+    if host.is_empty() {
+        return Some(SocketAddr::new(default, port));
+    }
+    let ip: IpAddr = host.parse().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slot() {
+        assert_eq!(hash_slot(b"123456789"), 0x31C3);
+        assert_eq!(hash_slot(b"foo"), 12182);
+        assert_eq!(hash_slot(b"{user1000}.following"), hash_slot(b"user1000"));
+        assert_eq!(hash_slot(b"{user1000}.followers"), hash_slot(b"user1000"));
+        // an empty hash tag is ignored
+        assert_eq!(
+            hash_slot(b"foo{}{bar}"),
+            CRC.checksum(b"foo{}{bar}") % 16384
+        );
+        assert_eq!(hash_slot(b"foo{{bar}}zap"), hash_slot(b"{bar"));
+    }
+
+    #[test]
+    fn update() {
+        let seed: SocketAddr = "127.0.0.1:30001".parse().unwrap();
+        let mut cluster = Cluster::new(vec![seed]);
+        assert!(cluster.needs_refresh());
+        assert_eq!(cluster.route(b"foo"), Some(seed));
+
+        cluster.update(
+            vec![
+                SlotRange {
+                    start: 0,
+                    end: 8191,
+                    host: b"127.0.0.1".to_vec(),
+                    port: 30001,
+                },
+                SlotRange {
+                    start: 8192,
+                    end: 16383,
+                    host: Vec::new(),
+                    port: 30002,
+                },
+            ],
+            seed,
+        );
+        let node: SocketAddr = "127.0.0.1:30002".parse().unwrap();
+        assert_eq!(cluster.take_added(), vec![node]);
+        assert!(!cluster.needs_refresh());
+        assert_eq!(cluster.route(b"foo"), Some(node));
+        assert!(!cluster.needs_refresh());
+
+        // a redirected request is retried and the topology is refreshed
+        let token = Token(1);
+        cluster.sent(token, b"request");
+        cluster.moved(token, 12182, seed, None);
+        assert!(cluster.take_added().is_empty());
+        assert_eq!(cluster.route(b"foo"), Some(seed));
+        assert!(cluster.needs_refresh());
+        assert_eq!(cluster.next_retry(), Some(seed));
+        assert_eq!(
+            cluster.pop_retry().map(|r| r.request),
+            Some(b"request".to_vec())
+        );
+    }
+    #[test]
+    fn unassigned() {
+        let seed: SocketAddr = "127.0.0.1:30001".parse().unwrap();
+        let mut cluster = Cluster::new(vec![seed]);
+        cluster.update(
+            vec![
+                SlotRange {
+                    start: 0,
+                    end: 8191,
+                    host: Vec::new(),
+                    port: 30001,
+                },
+                // hostnames are not resolved
+                SlotRange {
+                    start: 8192,
+                    end: 16383,
+                    host: b"localhost".to_vec(),
+                    port: 30002,
+                },
+            ],
+            seed,
+        );
+        assert!(cluster.take_added().is_empty());
+        assert!(!cluster.needs_refresh());
+
+        // a key in a slot without an owner is sent to a seed node and the
+        // topology is refreshed
+        assert_eq!(cluster.route(b"foo"), Some(seed));
+        assert!(cluster.needs_refresh());
+    }
+}
@@ -3,52 +3,41 @@
 // http://www.apache.org/licenses/LICENSE-2.0
 
 use crate::codec::*;
-use crate::config::Keyspace;
 use crate::*;
 use crc::{Crc, CRC_32_ISO_HDLC};
 use std::io::BufRead;
 use std::io::Write;
 
-use rand::rngs::SmallRng;
-use rand::{Rng, SeedableRng};
-use rand_distr::Alphanumeric;
-
 const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
-pub struct Echo {
-    config: Arc<Config>,
-    rng: SmallRng,
-}
+#[derive(Default)]
+pub struct Echo;
 
 impl Echo {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self {
-            config,
-            rng: SmallRng::from_entropy(),
-        }
+    pub fn new() -> Self {
+        Self
     }
 
-    pub fn echo(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let value = rng
-            .sample_iter(&Alphanumeric)
-            .take(keyspace.length())
-            .collect::<Vec<u8>>();
-
+    pub fn echo(value: &[u8], buf: &mut Session) {
         let mut digest = CRC.digest();
-        digest.update(&value);
-        let _ = buf.write_all(&value);
+        digest.update(value);
+        let _ = buf.write_all(value);
         let _ = buf.write_all(&digest.finalize().to_be_bytes());
         let _ = buf.write_all(b"\r\n");
     }
 }
 
 impl Codec for Echo {
-    fn encode(&mut self, buf: &mut Session) {
-        let keyspace = self.config.choose_keyspace(&mut self.rng);
-        Self::echo(&mut self.rng, keyspace, buf)
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        match request {
+            Request::Echo { value } => Self::echo(value, buf),
+            _ => {
+                unimplemented!()
+            }
+        }
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -65,17 +54,17 @@ impl Codec for Echo {
                 let crc_calculated = digest.finalize();
                 let crc_calculated: [u8; 4] =
                     unsafe { std::mem::transmute(crc_calculated.to_be()) };
-                if crc_calculated != crc_received[..] {
+                let kind = if crc_calculated != crc_received[..] {
                     debug!(
                         "Response has bad CRC: {:?} != {:?}",
                         crc_received, crc_calculated
                     );
-                    metrics::RESPONSE_EX.increment();
-                    Err(ParseError::Error)
+                    ResponseKind::Error
                 } else {
-                    let _ = buffer.consume(response_end + 2);
-                    Ok(())
-                }
+                    ResponseKind::Ok
+                };
+                let _ = buffer.consume(response_end + 2);
+                Ok(Response::new(kind, 0, response_end + 2))
             }
         } else {
             Err(ParseError::Incomplete)
@@ -3,112 +3,210 @@
 // http://www.apache.org/licenses/LICENSE-2.0
 
 use crate::codec::*;
-use crate::config::*;
-use crate::config_file::Verb;
 use crate::*;
 use std::io::BufRead;
 use std::io::Write;
 
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
-
-pub struct Memcache {
-    config: Arc<Config>,
-    rng: SmallRng,
-}
+#[derive(Default)]
+pub struct Memcache;
 
 impl Memcache {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self {
-            config,
-            rng: SmallRng::from_entropy(),
-        }
+    pub fn new() -> Self {
+        Self
     }
 
-    fn get(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let _ = buf.write_all(b"get ");
+    fn get(command: &[u8], keys: &[Vec<u8>], buf: &mut Session) {
+        let _ = buf.write_all(command);
 
-        for i in 0..keyspace.batch_size() {
-            let key = keyspace.generate_key(rng);
-            let _ = buf.write_all(&key);
-            if i + 1 < keyspace.batch_size() {
-                let _ = buf.write_all(b" ");
-            }
+        for key in keys {
+            let _ = buf.write_all(b" ");
+            let _ = buf.write_all(key);
         }
 
         let _ = buf.write_all(b"\r\n");
     }
 
-    fn set(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let value = keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec());
-        let ttl = keyspace.ttl();
-        let _ = buf.write_all(b"set ");
-        let _ = buf.write_all(&key);
+    /// Writes one of the storage commands, `set`, `add`, or `replace`.
+    fn store(command: &[u8], key: &[u8], value: &[u8], ttl: u32, buf: &mut Session) {
+        let _ = buf.write_all(command);
+        let _ = buf.write_all(b" ");
+        let _ = buf.write_all(key);
         let _ = buf.write_all(format!(" 0 {} {}\r\n", ttl, value.len()).as_bytes());
-        let _ = buf.write_all(&value);
+        let _ = buf.write_all(value);
         let _ = buf.write_all(b"\r\n");
     }
 
-    fn delete(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn delete(key: &[u8], buf: &mut Session) {
         let _ = buf.write_all(b"delete ");
-        let _ = buf.write_all(&key);
+        let _ = buf.write_all(key);
         let _ = buf.write_all(b"\r\n");
     }
 }
 
 impl Codec for Memcache {
-    fn encode(&mut self, buf: &mut Session) {
-        let keyspace = self.config.choose_keyspace(&mut self.rng);
-        let command = keyspace.choose_command(&mut self.rng);
-        match command.verb() {
-            Verb::Get => {
-                metrics::REQUEST_GET.increment();
-                Self::get(&mut self.rng, keyspace, buf)
-            }
-            Verb::Set => Self::set(&mut self.rng, keyspace, buf),
-            Verb::Delete => Self::delete(&mut self.rng, keyspace, buf),
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        match request {
+            Request::Get { keys } => Self::get(b"get", keys, buf),
+            Request::Gets { keys } => Self::get(b"gets", keys, buf),
+            Request::Set { key, value, ttl } => Self::store(b"set", key, value, *ttl, buf),
+            Request::Add { key, value, ttl } => Self::store(b"add", key, value, *ttl, buf),
+            Request::Replace { key, value, ttl } => Self::store(b"replace", key, value, *ttl, buf),
+            Request::Delete { key } => Self::delete(key, buf),
             _ => {
                 unimplemented!()
             }
         }
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
-        for response in &[
-            "STORED\r\n",
-            "NOT_STORED\r\n",
-            "EXISTS\r\n",
-            "NOT_FOUND\r\n",
-            "DELETED\r\n",
-            "TOUCHED\r\n",
-        ] {
-            let bytes = response.as_bytes();
-            if buf.len() >= bytes.len() && &buf[0..bytes.len()] == bytes {
-                let _ = buffer.consume(bytes.len());
-                return Ok(());
-            }
+        let response = decode(buf, None)?;
+        buffer.consume(response.bytes);
+        Ok(response)
+    }
+
+    fn decode_items(&self, buffer: &mut Session) -> Result<(Response, Vec<Item>), ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+
+        let mut items = Vec::new();
+        let response = decode(buf, Some(&mut items))?;
+        buffer.consume(response.bytes);
+        Ok((response, items))
+    }
+}
+
+/// Parses a complete response from the buffer. The keys and values of a get
+/// response are added to the items if they are provided.
+fn decode(buf: &[u8], mut items: Option<&mut Vec<Item>>) -> Result<Response, ParseError> {
+    for (response, kind) in &[
+        ("STORED\r\n", ResponseKind::Stored),
+        ("NOT_STORED\r\n", ResponseKind::NotStored),
+        ("EXISTS\r\n", ResponseKind::NotStored),
+        ("NOT_FOUND\r\n", ResponseKind::Miss),
+        ("DELETED\r\n", ResponseKind::Ok),
+        ("TOUCHED\r\n", ResponseKind::Ok),
+    ] {
+        let bytes = response.as_bytes();
+        if buf.len() >= bytes.len() && &buf[0..bytes.len()] == bytes {
+            return Ok(Response::new(*kind, 0, bytes.len()));
         }
+    }
 
-        let mut windows = buf.windows(5);
-        if let Some(response_end) = windows.position(|w| w == b"END\r\n") {
-            let response = &buf[0..(response_end + 5)];
-            let mut start = 0;
-            let mut lines = response.windows(2);
-            while let Some(line_end) = lines.position(|w| w == b"\r\n") {
-                if response.len() >= 5 && &response[start..(start + 5)] == b"VALUE" {
-                    metrics::RESPONSE_HIT.increment();
-                }
-                start = line_end + 2;
-            }
-            buffer.consume(response_end + 5);
-            return Ok(());
+    // errors are a single line which may include a message
+    for error in &[&b"ERROR"[..], b"CLIENT_ERROR", b"SERVER_ERROR"] {
+        if buf.starts_with(error) {
+            let mut lines = buf.windows(2);
+            return match lines.position(|w| w == b"\r\n") {
+                Some(line_end) => Ok(Response::new(ResponseKind::Error, 0, line_end + 2)),
+                None => Err(ParseError::Incomplete),
+            };
         }
+    }
+
+    // a get response is any number of values followed by `END`
+    let mut offset = 0;
+    let mut hits = 0;
+    loop {
+        let remaining = &buf[offset..];
+        let line_end = match remaining.windows(2).position(|w| w == b"\r\n") {
+            Some(line_end) => line_end,
+            None => return Err(ParseError::Incomplete),
+        };
+        let line = &remaining[0..line_end];
+        if line == b"END" {
+            offset += line_end + 2;
+            break;
+        }
+        if !line.starts_with(b"VALUE ") {
+            return Err(ParseError::Unknown);
+        }
+        let mut fields = line.split(|b| *b == b' ');
+        let key = fields.nth(1).ok_or(ParseError::Unknown)?;
+        let size = fields
+            .nth(1)
+            .and_then(|s| std::str::from_utf8(s).ok())
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or(ParseError::Unknown)?;
+        let start = offset + line_end + 2;
+        offset = start
+            .checked_add(size)
+            .and_then(|end| end.checked_add(2))
+            .ok_or(ParseError::Unknown)?;
+        if buf.len() < offset {
+            return Err(ParseError::Incomplete);
+        }
+        // the data block is terminated by a CRLF
+        if &buf[(start + size)..offset] != b"\r\n" {
+            return Err(ParseError::Unknown);
+        }
+        if let Some(ref mut items) = items {
+            items.push(Item {
+                key: Some(key.to_vec()),
+                value: Some(buf[start..(start + size)].to_vec()),
+            });
+        }
+        hits += 1;
+    }
+
+    let kind = if hits > 0 {
+        ResponseKind::Hit
+    } else {
+        ResponseKind::Miss
+    };
+    Ok(Response::new(kind, hits, offset))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_responses() {
+        assert_eq!(
+            decode(b"STORED\r\n", None),
+            Ok(Response::new(ResponseKind::Stored, 0, 8))
+        );
+        assert_eq!(
+            decode(b"NOT_STORED\r\n", None),
+            Ok(Response::new(ResponseKind::NotStored, 0, 12))
+        );
+        assert_eq!(
+            decode(b"SERVER_ERROR out of memory\r\n", None),
+            Ok(Response::new(ResponseKind::Error, 0, 28))
+        );
+        assert_eq!(
+            decode(b"END\r\n", None),
+            Ok(Response::new(ResponseKind::Miss, 0, 5))
+        );
+
+        // a value may contain the terminator
+        let buf = b"VALUE a 0 5\r\nEND\r\n\r\nVALUE b 0 1\r\nx\r\nEND\r\n";
+        assert_eq!(decode(&buf[0..20], None), Err(ParseError::Incomplete));
+        assert_eq!(
+            decode(buf, None),
+            Ok(Response::new(ResponseKind::Hit, 2, buf.len()))
+        );
+
+        let mut items = Vec::new();
+        decode(buf, Some(&mut items)).unwrap();
+        let b = Item {
+            key: Some(b"b".to_vec()),
+            value: Some(b"x".to_vec()),
+        };
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1], b);
+    }
+
+    #[test]
+    fn decode_invalid_values() {
+        // a length which would overflow the offset
+        let buf = b"VALUE a 0 18446744073709551615\r\nx\r\nEND\r\n";
+        assert_eq!(decode(buf, None), Err(ParseError::Unknown));
 
-        Err(ParseError::Incomplete)
+        // the data block must be followed by a CRLF
+        let buf = b"VALUE a 0 1\r\nxyzEND\r\n";
+        assert_eq!(decode(buf, None), Err(ParseError::Unknown));
     }
 }
@@ -0,0 +1,350 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Implements the memcache binary protocol. Each request and response begins
+//! with a 24 byte header which is followed by the extras, key, and value.
+//!
+//! A get with a single key is sent as `GET` unless the flags of the get
+//! command select another opcode: `k` has the key returned with the value
+//! (`GETK`), and `q` sends a quiet get which is terminated by a noop (`GETQ`).
+//! Both may be combined (`GETKQ`). A multi-get is always sent as quiet gets
+//! which return the key.
+
+use crate::codec::*;
+use crate::config_file::Verb;
+use crate::*;
+use std::io::BufRead;
+use std::io::Write;
+
+const HEADER_LEN: usize = 24;
+
+const REQUEST_MAGIC: u8 = 0x80;
+const RESPONSE_MAGIC: u8 = 0x81;
+
+const OPCODE_GET: u8 = 0x00;
+const OPCODE_SET: u8 = 0x01;
+const OPCODE_DELETE: u8 = 0x04;
+const OPCODE_GETQ: u8 = 0x09;
+const OPCODE_NOOP: u8 = 0x0a;
+const OPCODE_GETK: u8 = 0x0c;
+const OPCODE_GETKQ: u8 = 0x0d;
+const OPCODE_SASL_AUTH: u8 = 0x21;
+
+const STATUS_NO_ERROR: u16 = 0x0000;
+const STATUS_KEY_NOT_FOUND: u16 = 0x0001;
+const STATUS_KEY_EXISTS: u16 = 0x0002;
+const STATUS_ITEM_NOT_STORED: u16 = 0x0005;
+
+pub struct MemcacheBinary {
+    config: Arc<Config>,
+    opaque: u32,
+    /// the opcode used for a get with a single key
+    get_opcode: u8,
+}
+
+impl MemcacheBinary {
+    pub fn new(config: Arc<Config>) -> Self {
+        let flags = config
+            .keyspaces()
+            .iter()
+            .flat_map(|k| k.commands())
+            .find(|c| c.verb() == Verb::Get)
+            .and_then(|c| c.flags())
+            .unwrap_or_default();
+        let get_opcode = get_opcode(&flags).unwrap_or_else(|| {
+            fatal!("invalid flags for get: {}", flags);
+        });
+        Self {
+            config,
+            opaque: 0,
+            get_opcode,
+        }
+    }
+
+    /// Writes a single request packet into the buffer.
+    fn request(
+        buf: &mut Session,
+        opcode: u8,
+        opaque: u32,
+        extras: &[u8],
+        key: &[u8],
+        value: &[u8],
+    ) {
+        let body_len = extras.len() + key.len() + value.len();
+        let mut header = [0_u8; HEADER_LEN];
+        header[0] = REQUEST_MAGIC;
+        header[1] = opcode;
+        header[2..4].copy_from_slice(&(key.len() as u16).to_be_bytes());
+        header[4] = extras.len() as u8;
+        header[8..12].copy_from_slice(&(body_len as u32).to_be_bytes());
+        header[12..16].copy_from_slice(&opaque.to_be_bytes());
+        let _ = buf.write_all(&header);
+        let _ = buf.write_all(extras);
+        let _ = buf.write_all(key);
+        let _ = buf.write_all(value);
+    }
+
+    /// Sends a get with the opcode, the quiet gets are terminated by a noop.
+    fn get(opaque: &mut u32, opcode: u8, key: &[u8], buf: &mut Session) {
+        *opaque = opaque.wrapping_add(1);
+        Self::request(buf, opcode, *opaque, &[], key, &[]);
+        if opcode == OPCODE_GETQ || opcode == OPCODE_GETKQ {
+            *opaque = opaque.wrapping_add(1);
+            Self::request(buf, OPCODE_NOOP, *opaque, &[], &[], &[]);
+        }
+    }
+
+    /// A multi-get is sent as a series of quiet gets which are terminated by
+    /// a noop. Only hits generate a response for the quiet gets, the noop
+    /// response indicates that the server has processed the entire batch.
+    fn multi_get(opaque: &mut u32, keys: &[Vec<u8>], buf: &mut Session) {
+        for key in keys {
+            *opaque = opaque.wrapping_add(1);
+            Self::request(buf, OPCODE_GETKQ, *opaque, &[], key, &[]);
+        }
+        *opaque = opaque.wrapping_add(1);
+        Self::request(buf, OPCODE_NOOP, *opaque, &[], &[], &[]);
+    }
+
+    fn set(opaque: &mut u32, key: &[u8], value: &[u8], ttl: u32, buf: &mut Session) {
+        // extras are the flags followed by the expiration time
+        let mut extras = [0_u8; 8];
+        extras[4..8].copy_from_slice(&ttl.to_be_bytes());
+
+        *opaque = opaque.wrapping_add(1);
+        Self::request(buf, OPCODE_SET, *opaque, &extras, key, value);
+    }
+
+    fn delete(opaque: &mut u32, key: &[u8], buf: &mut Session) {
+        *opaque = opaque.wrapping_add(1);
+        Self::request(buf, OPCODE_DELETE, *opaque, &[], key, &[]);
+    }
+}
+
+impl Codec for MemcacheBinary {
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        let opaque = &mut self.opaque;
+        match request {
+            Request::Get { keys } => {
+                if keys.len() == 1 {
+                    Self::get(opaque, self.get_opcode, &keys[0], buf)
+                } else {
+                    Self::multi_get(opaque, keys, buf)
+                }
+            }
+            Request::Set { key, value, ttl } => Self::set(opaque, key, value, *ttl, buf),
+            Request::Delete { key } => Self::delete(opaque, key, buf),
+            _ => {
+                unimplemented!()
+            }
+        }
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+
+        let response = decode(buf)?;
+        buffer.consume(response.bytes);
+        Ok(response)
+    }
+
+    /// Authenticates the session using SASL `PLAIN` if credentials are
+    /// configured.
+    fn setup(&mut self, buf: &mut Session) -> usize {
+        let auth = self.config.auth();
+        if let Some(password) = auth.password() {
+            // the authorization identity is left empty
+            let mut value = vec![0];
+            value.extend_from_slice(auth.username().unwrap_or_default().as_bytes());
+            value.push(0);
+            value.extend_from_slice(password.as_bytes());
+
+            self.opaque = self.opaque.wrapping_add(1);
+            Self::request(buf, OPCODE_SASL_AUTH, self.opaque, &[], b"PLAIN", &value);
+            1
+        } else {
+            0
+        }
+    }
+}
+
+/// Returns the opcode for a single get with the flags, which are `k` and `q`
+/// separated by whitespace, or `None` if the flags are not valid.
+pub fn get_opcode(flags: &str) -> Option<u8> {
+    let mut key = false;
+    let mut quiet = false;
+    for flag in flags.split_whitespace() {
+        match flag {
+            "k" => key = true,
+            "q" => quiet = true,
+            _ => return None,
+        }
+    }
+    Some(match (key, quiet) {
+        (false, false) => OPCODE_GET,
+        (true, false) => OPCODE_GETK,
+        (false, true) => OPCODE_GETQ,
+        (true, true) => OPCODE_GETKQ,
+    })
+}
+
+/// Parses a complete response from the buffer. Responses to quiet gets are
+/// accumulated until the response which terminates the request is found.
+fn decode(buf: &[u8]) -> Result<Response, ParseError> {
+    let mut offset = 0;
+    let mut hits = 0;
+    let mut error = false;
+
+    loop {
+        let packet = &buf[offset..];
+        if packet.len() < HEADER_LEN {
+            return Err(ParseError::Incomplete);
+        }
+
+        if packet[0] != RESPONSE_MAGIC {
+            return Err(ParseError::Unknown);
+        }
+
+        let opcode = packet[1];
+        let status = u16::from_be_bytes([packet[6], packet[7]]);
+        let body_len = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]) as usize;
+
+        let packet_len = HEADER_LEN + body_len;
+        if packet.len() < packet_len {
+            return Err(ParseError::Incomplete);
+        }
+        offset += packet_len;
+
+        let kind = match (opcode, status) {
+            (OPCODE_GETQ, STATUS_NO_ERROR) | (OPCODE_GETKQ, STATUS_NO_ERROR) => {
+                // quiet get hit, the batch continues until the noop
+                hits += 1;
+                continue;
+            }
+            (OPCODE_GETQ, _) | (OPCODE_GETKQ, _) => {
+                // quiet gets do not respond to misses, anything else is an
+                // error for this key within the batch
+                error = true;
+                continue;
+            }
+            (OPCODE_GET, STATUS_NO_ERROR) | (OPCODE_GETK, STATUS_NO_ERROR) => {
+                hits += 1;
+                ResponseKind::Hit
+            }
+            (OPCODE_NOOP, STATUS_NO_ERROR) if hits > 0 => ResponseKind::Hit,
+            (OPCODE_NOOP, STATUS_NO_ERROR) => ResponseKind::Miss,
+            (OPCODE_SET, STATUS_NO_ERROR) => ResponseKind::Stored,
+            (_, STATUS_NO_ERROR) => ResponseKind::Ok,
+            (_, STATUS_KEY_NOT_FOUND) => ResponseKind::Miss,
+            (_, STATUS_KEY_EXISTS) | (_, STATUS_ITEM_NOT_STORED) => ResponseKind::NotStored,
+            _ => ResponseKind::Error,
+        };
+
+        let kind = if error { ResponseKind::Error } else { kind };
+        return Ok(Response::new(kind, hits, offset));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn response(opcode: u8, status: u16, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let extras: &[u8] = if value.is_empty() { &[] } else { &[0; 4] };
+        let body_len = extras.len() + key.len() + value.len();
+        let mut packet = vec![0; HEADER_LEN];
+        packet[0] = RESPONSE_MAGIC;
+        packet[1] = opcode;
+        packet[2..4].copy_from_slice(&(key.len() as u16).to_be_bytes());
+        packet[4] = extras.len() as u8;
+        packet[6..8].copy_from_slice(&status.to_be_bytes());
+        packet[8..12].copy_from_slice(&(body_len as u32).to_be_bytes());
+        packet.extend_from_slice(extras);
+        packet.extend_from_slice(key);
+        packet.extend_from_slice(value);
+        packet
+    }
+
+    #[test]
+    fn get() {
+        let hit = response(OPCODE_GET, STATUS_NO_ERROR, b"", b"value");
+        assert_eq!(decode(&hit[0..10]), Err(ParseError::Incomplete));
+        assert_eq!(
+            decode(&hit[0..(hit.len() - 1)]),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            decode(&hit),
+            Ok(Response::new(ResponseKind::Hit, 1, hit.len()))
+        );
+
+        let miss = response(OPCODE_GET, STATUS_KEY_NOT_FOUND, b"", b"");
+        assert_eq!(
+            decode(&miss),
+            Ok(Response::new(ResponseKind::Miss, 0, HEADER_LEN))
+        );
+    }
+
+    #[test]
+    fn get_opcodes() {
+        assert_eq!(get_opcode(""), Some(OPCODE_GET));
+        assert_eq!(get_opcode("k"), Some(OPCODE_GETK));
+        assert_eq!(get_opcode("q"), Some(OPCODE_GETQ));
+        assert_eq!(get_opcode("q k"), Some(OPCODE_GETKQ));
+        assert_eq!(get_opcode("v"), None);
+
+        let hit = response(OPCODE_GETK, STATUS_NO_ERROR, b"a", b"value");
+        assert_eq!(
+            decode(&hit),
+            Ok(Response::new(ResponseKind::Hit, 1, hit.len()))
+        );
+
+        // a quiet get which misses only has the noop response
+        let mut buf = response(OPCODE_GETQ, STATUS_NO_ERROR, b"", b"value");
+        buf.extend_from_slice(&response(OPCODE_NOOP, STATUS_NO_ERROR, b"", b""));
+        assert_eq!(
+            decode(&buf),
+            Ok(Response::new(ResponseKind::Hit, 1, buf.len()))
+        );
+        let noop = response(OPCODE_NOOP, STATUS_NO_ERROR, b"", b"");
+        assert_eq!(
+            decode(&noop),
+            Ok(Response::new(ResponseKind::Miss, 0, HEADER_LEN))
+        );
+    }
+
+    #[test]
+    fn multi_get() {
+        let mut buf = response(OPCODE_GETKQ, STATUS_NO_ERROR, b"a", b"value");
+        buf.extend_from_slice(&response(OPCODE_GETKQ, STATUS_NO_ERROR, b"c", b"value"));
+        assert_eq!(decode(&buf), Err(ParseError::Incomplete));
+
+        buf.extend_from_slice(&response(OPCODE_NOOP, STATUS_NO_ERROR, b"", b""));
+        assert_eq!(
+            decode(&buf),
+            Ok(Response::new(ResponseKind::Hit, 2, buf.len()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let stored = response(OPCODE_SET, STATUS_ITEM_NOT_STORED, b"", b"");
+        assert_eq!(
+            decode(&stored),
+            Ok(Response::new(ResponseKind::NotStored, 0, HEADER_LEN))
+        );
+
+        // out of memory
+        let oom = response(OPCODE_SET, 0x0082, b"", b"");
+        assert_eq!(
+            decode(&oom),
+            Ok(Response::new(ResponseKind::Error, 0, HEADER_LEN))
+        );
+
+        let mut bad = response(OPCODE_GET, STATUS_NO_ERROR, b"", b"");
+        bad[0] = REQUEST_MAGIC;
+        assert_eq!(decode(&bad), Err(ParseError::Unknown));
+    }
+}
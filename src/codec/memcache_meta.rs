@@ -0,0 +1,275 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Implements the memcache meta protocol (`mg`, `ms`, `md`, `ma`, `mn`).
+//!
+//! Flags for each command are taken from the command configuration. The
+//! opaque (`O`) flag is reserved by the codec: it is used to tag `mg` requests
+//! so their responses can be recognized as hits or as part of a multi-get.
+
+use crate::codec::*;
+use crate::config_file::Verb;
+use crate::*;
+use std::io::BufRead;
+use std::io::Write;
+
+/// opaque token attached to a single `mg`
+const GET_OPAQUE: &[u8] = b"Og";
+/// opaque token attached to each quiet `mg` within a multi-get
+const MULTI_GET_OPAQUE: &[u8] = b"Om";
+
+pub struct MemcacheMeta {
+    /// the flags configured for each verb
+    flags: Vec<(Verb, Option<String>)>,
+}
+
+impl MemcacheMeta {
+    pub fn new(config: Arc<Config>) -> Self {
+        let mut flags: Vec<(Verb, Option<String>)> = Vec::new();
+        for command in config.keyspaces().iter().flat_map(|k| k.commands()) {
+            if !flags.iter().any(|(verb, _)| *verb == command.verb()) {
+                flags.push((command.verb(), command.flags()));
+            }
+        }
+        Self { flags }
+    }
+
+    /// Writes the space separated flags, the flags configured for the command
+    /// are written after any flags provided by the codec.
+    fn flags(buf: &mut Session, flags: &[&[u8]], configured: Option<&str>) {
+        for flag in flags {
+            let _ = buf.write_all(b" ");
+            let _ = buf.write_all(flag);
+        }
+        if let Some(flags) = configured {
+            for flag in flags.split_whitespace() {
+                let _ = buf.write_all(b" ");
+                let _ = buf.write_all(flag.as_bytes());
+            }
+        }
+    }
+
+    fn get(key: &[u8], configured: Option<&str>, buf: &mut Session) {
+        let _ = buf.write_all(b"mg ");
+        let _ = buf.write_all(key);
+        if configured.is_none() {
+            Self::flags(buf, &[b"v", GET_OPAQUE], configured);
+        } else {
+            Self::flags(buf, &[GET_OPAQUE], configured);
+        }
+        let _ = buf.write_all(b"\r\n");
+    }
+
+    /// A multi-get is sent as a series of quiet `mg` which are terminated by
+    /// an `mn`. Misses do not generate a response for quiet gets, the `MN`
+    /// response indicates that the server has processed the entire batch.
+    fn multi_get(keys: &[Vec<u8>], configured: Option<&str>, buf: &mut Session) {
+        for key in keys {
+            let _ = buf.write_all(b"mg ");
+            let _ = buf.write_all(key);
+            if configured.is_none() {
+                Self::flags(buf, &[b"v", b"q", MULTI_GET_OPAQUE], configured);
+            } else {
+                Self::flags(buf, &[b"q", MULTI_GET_OPAQUE], configured);
+            }
+            let _ = buf.write_all(b"\r\n");
+        }
+        let _ = buf.write_all(b"mn\r\n");
+    }
+
+    fn set(key: &[u8], value: &[u8], ttl: u32, configured: Option<&str>, buf: &mut Session) {
+        let _ = buf.write_all(b"ms ");
+        let _ = buf.write_all(key);
+        let _ = buf.write_all(format!(" {}", value.len()).as_bytes());
+        if ttl != 0 {
+            let ttl = format!("T{}", ttl);
+            Self::flags(buf, &[ttl.as_bytes()], configured);
+        } else {
+            Self::flags(buf, &[], configured);
+        }
+        let _ = buf.write_all(b"\r\n");
+        let _ = buf.write_all(value);
+        let _ = buf.write_all(b"\r\n");
+    }
+
+    fn delete(key: &[u8], configured: Option<&str>, buf: &mut Session) {
+        let _ = buf.write_all(b"md ");
+        let _ = buf.write_all(key);
+        Self::flags(buf, &[], configured);
+        let _ = buf.write_all(b"\r\n");
+    }
+
+    fn incr(key: &[u8], configured: Option<&str>, buf: &mut Session) {
+        let _ = buf.write_all(b"ma ");
+        let _ = buf.write_all(key);
+        Self::flags(buf, &[], configured);
+        let _ = buf.write_all(b"\r\n");
+    }
+}
+
+impl Codec for MemcacheMeta {
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        let verb = request.verb();
+        let configured = self
+            .flags
+            .iter()
+            .find(|(v, _)| *v == verb)
+            .and_then(|(_, flags)| flags.as_deref());
+        match request {
+            Request::Get { keys } => {
+                if keys.len() == 1 {
+                    Self::get(&keys[0], configured, buf)
+                } else {
+                    Self::multi_get(keys, configured, buf)
+                }
+            }
+            Request::Set { key, value, ttl } => Self::set(key, value, *ttl, configured, buf),
+            Request::Delete { key } => Self::delete(key, configured, buf),
+            Request::Incr { key } => Self::incr(key, configured, buf),
+            _ => {
+                unimplemented!()
+            }
+        }
+    }
+
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
+        // no-copy borrow as a slice
+        let buf: &[u8] = (*buffer).buffer();
+
+        let response = decode(buf)?;
+        buffer.consume(response.bytes);
+        Ok(response)
+    }
+}
+
+/// Parses a complete response from the buffer. Responses to the quiet gets in
+/// a multi-get are accumulated until the `MN` which terminates the batch.
+fn decode(buf: &[u8]) -> Result<Response, ParseError> {
+    let mut offset = 0;
+    let mut hits = 0;
+
+    loop {
+        let remaining = &buf[offset..];
+
+        let line_end = match remaining.windows(2).position(|w| w == b"\r\n") {
+            Some(line_end) => line_end,
+            None => return Err(ParseError::Incomplete),
+        };
+        let line = &remaining[0..line_end];
+        let mut tokens = line.split(|b| *b == b' ').filter(|t| !t.is_empty());
+        let code = tokens.next().ok_or(ParseError::Unknown)?;
+
+        let mut len = line_end + 2;
+
+        match code {
+            b"VA" | b"HD" => {
+                if code == b"VA" {
+                    let size = tokens.next().ok_or(ParseError::Unknown)?;
+                    let size = std::str::from_utf8(size)
+                        .map_err(|_| ParseError::Unknown)?
+                        .parse::<usize>()
+                        .map_err(|_| ParseError::Unknown)?;
+                    len += size + 2;
+                    if remaining.len() < len {
+                        return Err(ParseError::Incomplete);
+                    }
+                }
+                offset += len;
+
+                let mut get = false;
+                let mut batch = false;
+                for flag in tokens {
+                    if flag == GET_OPAQUE {
+                        get = true;
+                    } else if flag == MULTI_GET_OPAQUE {
+                        get = true;
+                        batch = true;
+                    }
+                }
+                if get {
+                    hits += 1;
+                }
+                if !batch {
+                    // without a value, `HD` does not tell a store from other
+                    // successful requests
+                    let kind = if get {
+                        ResponseKind::Hit
+                    } else {
+                        ResponseKind::Ok
+                    };
+                    return Ok(Response::new(kind, hits, offset));
+                }
+            }
+            b"EN" | b"NF" => {
+                offset += len;
+                return Ok(Response::new(ResponseKind::Miss, hits, offset));
+            }
+            b"NS" | b"EX" => {
+                offset += len;
+                return Ok(Response::new(ResponseKind::NotStored, hits, offset));
+            }
+            b"MN" => {
+                offset += len;
+                let kind = if hits > 0 {
+                    ResponseKind::Hit
+                } else {
+                    ResponseKind::Miss
+                };
+                return Ok(Response::new(kind, hits, offset));
+            }
+            b"SERVER_ERROR" | b"CLIENT_ERROR" | b"ERROR" => {
+                offset += len;
+                return Ok(Response::new(ResponseKind::Error, hits, offset));
+            }
+            _ => {
+                return Err(ParseError::Unknown);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn get() {
+        assert_eq!(decode(b"VA 5 Og\r\nhel"), Err(ParseError::Incomplete));
+        assert_eq!(
+            decode(b"VA 5 Og\r\nhello\r\n"),
+            Ok(Response::new(ResponseKind::Hit, 1, 16))
+        );
+        assert_eq!(
+            decode(b"HD Og t-1\r\n"),
+            Ok(Response::new(ResponseKind::Hit, 1, 11))
+        );
+        assert_eq!(
+            decode(b"EN\r\n"),
+            Ok(Response::new(ResponseKind::Miss, 0, 4))
+        );
+    }
+
+    #[test]
+    fn multi_get() {
+        let buf = b"VA 1 Om\r\na\r\nHD Om\r\n";
+        assert_eq!(decode(buf), Err(ParseError::Incomplete));
+
+        let buf = b"VA 1 Om\r\na\r\nHD Om\r\nMN\r\n";
+        assert_eq!(
+            decode(buf),
+            Ok(Response::new(ResponseKind::Hit, 2, buf.len()))
+        );
+    }
+
+    #[test]
+    fn other() {
+        // a stored response is not a hit
+        assert_eq!(decode(b"HD\r\n"), Ok(Response::new(ResponseKind::Ok, 0, 4)));
+        assert_eq!(
+            decode(b"SERVER_ERROR out of memory\r\n"),
+            Ok(Response::new(ResponseKind::Error, 0, 28))
+        );
+        assert_eq!(decode(b"STORED\r\n"), Err(ParseError::Unknown));
+    }
+}
@@ -4,16 +4,22 @@
 
 mod echo;
 mod memcache;
+mod memcache_binary;
+mod memcache_meta;
 mod ping;
 mod redis;
 mod thrift;
 mod thrift_cache;
 
+use crate::request::Request;
 use crate::Session;
 pub use echo::Echo;
 pub use memcache::Memcache;
+pub use memcache_binary::MemcacheBinary;
+pub use memcache_meta::MemcacheMeta;
 pub use ping::Ping;
-pub use redis::Redis;
+pub use redis::{cluster_slots, Redis, SlotRange};
+use std::net::SocketAddr;
 pub use thrift_cache::ThriftCache;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -21,9 +27,93 @@ pub enum ParseError {
     Incomplete,
     Error,
     Unknown,
+    /// The request must be sent to the endpoint which now serves the slot.
+    /// The response has been consumed.
+    Moved {
+        slot: u16,
+        endpoint: SocketAddr,
+    },
+    /// The request must be sent once to the endpoint which is importing the
+    /// slot, preceded by `ASKING`. The response has been consumed.
+    Ask {
+        slot: u16,
+        endpoint: SocketAddr,
+    },
+}
+
+/// The outcome of a request as reported by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResponseKind {
+    /// The request succeeded. Used for responses which do not indicate
+    /// whether an item was found or stored.
+    Ok,
+    /// At least one of the requested items was found.
+    Hit,
+    /// None of the requested items were found.
+    Miss,
+    Stored,
+    NotStored,
+    /// The server understood the request but returned an error.
+    Error,
+}
+
+/// A complete response which has been decoded and consumed from the session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Response {
+    pub kind: ResponseKind,
+    /// the number of items which were returned, such as the keys found by a
+    /// multi-get
+    pub items: usize,
+    /// the length of the response in bytes
+    pub bytes: usize,
+}
+
+impl Response {
+    pub fn new(kind: ResponseKind, items: usize, bytes: usize) -> Self {
+        Self { kind, items, bytes }
+    }
+}
+
+/// A value which was returned in a response. The key is known if the protocol
+/// returns it along with the value. Otherwise, the items are in the order of
+/// the keys in the request, and a missing value is returned as `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Item {
+    pub key: Option<Vec<u8>>,
+    pub value: Option<Vec<u8>>,
 }
 
 pub trait Codec: Send {
-    fn decode(&self, buf: &mut Session) -> Result<(), ParseError>;
-    fn encode(&mut self, buf: &mut Session);
+    /// Decodes and consumes a complete response. Errors are only returned if
+    /// the response is incomplete, could not be parsed, or must be sent to
+    /// another endpoint. Errors reported by the server are returned as a
+    /// `Response` with `ResponseKind::Error`.
+    fn decode(&self, buf: &mut Session) -> Result<Response, ParseError>;
+
+    /// Decodes and consumes a complete response like `decode`, and also
+    /// returns the values it contains so that they can be verified. Protocols
+    /// which do not support this return no items.
+    fn decode_items(&self, buf: &mut Session) -> Result<(Response, Vec<Item>), ParseError> {
+        self.decode(buf).map(|response| (response, Vec::new()))
+    }
+
+    /// Writes the request to the session. Panics if the request is not
+    /// supported by the protocol.
+    fn encode(&mut self, buf: &mut Session, request: &Request);
+
+    /// Writes any commands which must complete on a newly connected session
+    /// before it can be used for requests. Returns the number of responses
+    /// which are expected.
+    fn setup(&mut self, _buf: &mut Session) -> usize {
+        0
+    }
+
+    /// Decodes a response to one of the commands written by `setup`. An error
+    /// from the server fails the setup.
+    fn decode_setup(&self, buf: &mut Session) -> Result<(), ParseError> {
+        match self.decode(buf)?.kind {
+            ResponseKind::Error => Err(ParseError::Error),
+            _ => Ok(()),
+        }
+    }
 }
@@ -4,13 +4,13 @@
 
 use crate::codec::*;
 use crate::Session;
-use crate::*;
 use std::io::{BufRead, Write};
 
+#[derive(Default)]
 pub struct Ping;
 
 impl Ping {
-    pub fn new(_config: Arc<Config>) -> Self {
+    pub fn new() -> Self {
         Self
     }
 
@@ -20,11 +20,16 @@ impl Ping {
 }
 
 impl Codec for Ping {
-    fn encode(&mut self, buf: &mut Session) {
-        Self::ping(buf)
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        match request {
+            Request::Ping => Self::ping(buf),
+            _ => {
+                unimplemented!()
+            }
+        }
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -34,7 +39,7 @@ impl Codec for Ping {
             match &buf[0..response_end] {
                 b"pong" | b"PONG" => {
                     let _ = buffer.consume(response_end + 2);
-                    Ok(())
+                    Ok(Response::new(ResponseKind::Ok, 0, response_end + 2))
                 }
                 _ => Err(ParseError::Unknown),
             }
@@ -4,24 +4,23 @@
 
 use crate::codec::*;
 use crate::config::*;
-use crate::config_file::{Protocol, Verb};
+use crate::config_file::Protocol;
 use crate::*;
 
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
-
 use std::io::{BufRead, Write};
+use std::net::SocketAddr;
 use std::str;
 
 pub enum Mode {
     Inline,
     Resp,
+    /// RESP with protocol version 3 negotiated by `HELLO 3` on each new session
+    Resp3,
 }
 
 pub struct Redis {
     config: Arc<Config>,
     mode: Mode,
-    rng: SmallRng,
 }
 
 impl Redis {
@@ -29,240 +28,708 @@ impl Redis {
         let mode = match config.general().protocol() {
             Protocol::Redis | Protocol::RedisInline => Mode::Inline,
             Protocol::RedisResp => Mode::Resp,
+            Protocol::RedisResp3 => Mode::Resp3,
             unknown => {
                 fatal!("protocol: {:?} is not a redis protocol", unknown);
             }
         };
-        Self {
-            config,
-            mode,
-            rng: SmallRng::from_entropy(),
-        }
+        Self { config, mode }
     }
 
-    fn command(buf: &mut Session, mode: &Mode, command: &str, args: Vec<Vec<u8>>) {
+    fn command(buf: &mut Session, mode: &Mode, command: &str, args: &[&[u8]]) {
         match mode {
             Mode::Inline => {
                 let _ = buf.write_all(command.to_string().as_bytes());
                 for arg in args {
                     let _ = buf.write_all(b" ");
-                    let _ = buf.write_all(&arg);
+                    let _ = buf.write_all(arg);
                 }
                 let _ = buf.write_all(b"\r\n");
             }
-            Mode::Resp => {
+            Mode::Resp | Mode::Resp3 => {
                 let _ = buf.write_all(
                     format!("*{}\r\n${}\r\n{}", 1 + args.len(), command.len(), command).as_bytes(),
                 );
                 for arg in args {
                     let _ = buf.write_all(format!("\r\n${}\r\n", arg.len()).as_bytes());
-                    let _ = buf.write_all(&arg);
+                    let _ = buf.write_all(arg);
                 }
                 let _ = buf.write_all(b"\r\n");
             }
         }
     }
 
-    fn get(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let args = vec![keyspace.generate_key(rng)];
-        Redis::command(buf, mode, "get", args);
+    fn get(mode: &Mode, keys: &[Vec<u8>], buf: &mut Session) {
+        let args: Vec<&[u8]> = keys.iter().map(|k| k.as_slice()).collect();
+        if keys.len() == 1 {
+            Redis::command(buf, mode, "get", &args);
+        } else {
+            Redis::command(buf, mode, "mget", &args);
+        }
     }
 
-    fn mget(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let mut args = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            args.push(keyspace.generate_key(rng));
+    /// A set with a condition, `NX` or `XX`, is only stored if the key does
+    /// not exist or already exists respectively.
+    fn set(
+        mode: &Mode,
+        key: &[u8],
+        value: &[u8],
+        ttl: u32,
+        condition: Option<&[u8]>,
+        buf: &mut Session,
+    ) {
+        let ttl = (ttl != 0).then(|| ttl.to_string());
+        let mut args = vec![key, value];
+        if let Some(ref ttl) = ttl {
+            args.push(b"EX");
+            args.push(ttl.as_bytes());
         }
-        Redis::command(buf, mode, "mget", args);
-    }
-
-    fn set(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "set";
-        let mut args = vec![
-            keyspace.generate_key(rng),
-            keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()),
-        ];
-        let ttl = keyspace.ttl();
-        if ttl != 0 {
-            args.push(b"EX".to_vec());
-            args.push(format!("{}", ttl).as_bytes().to_vec());
+        args.extend(condition);
+        Redis::command(buf, mode, "set", &args);
+    }
+
+    fn del(mode: &Mode, key: &[u8], buf: &mut Session) {
+        Redis::command(buf, mode, "del", &[key]);
+    }
+
+    /// A hash get for more than one field is sent as an `hmget`.
+    fn hget(mode: &Mode, key: &[u8], fields: &[Vec<u8>], buf: &mut Session) {
+        let mut args = vec![key];
+        args.extend(fields.iter().map(|f| f.as_slice()));
+        if fields.len() == 1 {
+            Redis::command(buf, mode, "hget", &args);
+        } else {
+            Redis::command(buf, mode, "hmget", &args);
+        }
+    }
+
+    fn hset(mode: &Mode, key: &[u8], fields: &[Vec<u8>], values: &[Vec<u8>], buf: &mut Session) {
+        let mut args = vec![key];
+        for (field, value) in fields.iter().zip(values) {
+            args.push(field);
+            args.push(value);
         }
-        Redis::command(buf, mode, command, args);
-    }
-
-    fn del(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let args = vec![keyspace.generate_key(rng)];
-        Redis::command(buf, mode, "del", args);
-    }
-
-    fn hget(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "hget";
-        let args = vec![
-            keyspace.generate_key(rng),
-            keyspace
-                .generate_inner_key(rng)
-                .unwrap_or_else(|| b"".to_vec()),
-        ];
-        Redis::command(buf, mode, command, args);
-    }
-
-    fn hset(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "hset";
-        let args = vec![
-            keyspace.generate_key(rng),
-            keyspace
-                .generate_inner_key(rng)
-                .unwrap_or_else(|| b"".to_vec()),
-            keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()),
-        ];
-        Redis::command(buf, mode, command, args);
-    }
-
-    fn hsetnx(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "hsetnx";
-        let args = vec![
-            keyspace.generate_key(rng),
-            keyspace
-                .generate_inner_key(rng)
-                .unwrap_or_else(|| b"".to_vec()),
-            keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()),
-        ];
-        Redis::command(buf, mode, command, args);
-    }
-
-    fn hdel(rng: &mut SmallRng, mode: &Mode, keyspace: &Keyspace, buf: &mut Session) {
-        let command = "hdel";
-        let args = vec![
-            keyspace.generate_key(rng),
-            keyspace
-                .generate_inner_key(rng)
-                .unwrap_or_else(|| b"".to_vec()),
-        ];
-        Redis::command(buf, mode, command, args);
+        Redis::command(buf, mode, "hset", &args);
+    }
+
+    fn hsetnx(mode: &Mode, key: &[u8], field: &[u8], value: &[u8], buf: &mut Session) {
+        Redis::command(buf, mode, "hsetnx", &[key, field, value]);
+    }
+
+    fn hdel(mode: &Mode, key: &[u8], fields: &[Vec<u8>], buf: &mut Session) {
+        let mut args = vec![key];
+        args.extend(fields.iter().map(|f| f.as_slice()));
+        Redis::command(buf, mode, "hdel", &args);
     }
 }
 
 impl Codec for Redis {
-    fn encode(&mut self, buf: &mut Session) {
-        let keyspace = self.config.choose_keyspace(&mut self.rng);
-        let command = keyspace.choose_command(&mut self.rng);
-        match command.verb() {
-            Verb::Get => {
-                metrics::REQUEST_GET.increment();
-                if keyspace.batch_size() == 1 {
-                    Self::get(&mut self.rng, &self.mode, keyspace, buf)
-                } else {
-                    Self::mget(&mut self.rng, &self.mode, keyspace, buf)
-                }
-            }
-            Verb::Set => Self::set(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Delete => Self::del(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Hget => {
-                metrics::REQUEST_GET.increment();
-                Self::hget(&mut self.rng, &self.mode, keyspace, buf)
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        let mode = &self.mode;
+        match request {
+            // there are no compare-and-swap tokens, so a gets is a plain get
+            Request::Get { keys } | Request::Gets { keys } => Self::get(mode, keys, buf),
+            Request::Set { key, value, ttl } => Self::set(mode, key, value, *ttl, None, buf),
+            Request::Add { key, value, ttl } => Self::set(mode, key, value, *ttl, Some(b"NX"), buf),
+            Request::Replace { key, value, ttl } => {
+                Self::set(mode, key, value, *ttl, Some(b"XX"), buf)
             }
-            Verb::Hset => Self::hset(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Hsetnx => Self::hsetnx(&mut self.rng, &self.mode, keyspace, buf),
-            Verb::Hdel => Self::hdel(&mut self.rng, &self.mode, keyspace, buf),
+            Request::Delete { key } => Self::del(mode, key, buf),
+            Request::Hget { key, fields } => Self::hget(mode, key, fields, buf),
+            Request::Hset {
+                key,
+                fields,
+                values,
+                ..
+            } => Self::hset(mode, key, fields, values, buf),
+            Request::Hsetnx { key, field, value } => Self::hsetnx(mode, key, field, value, buf),
+            Request::Hdel { key, fields } => Self::hdel(mode, key, fields, buf),
             _ => {
                 unimplemented!()
             }
         }
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
-        if buf.len() < 1 {
-            return Err(ParseError::Incomplete);
+        if let Some((redirect, consumed)) = redirect(buf)? {
+            buffer.consume(consumed);
+            return Err(redirect);
         }
 
-        let first_char = &buf[0..1];
-        match str::from_utf8(first_char) {
-            Ok("+") => {
-                // simple string response
-                let mut lines = buf.windows(2);
-                if let Some(line_end) = lines.position(|w| w == b"\r\n") {
-                    let msg = &buf[1..line_end];
-                    match str::from_utf8(msg) {
-                        Ok("OK") | Ok("PONG") => {
-                            let _ = buffer.consume(line_end + 2);
-                            Ok(())
-                        }
-                        _ => Err(ParseError::Unknown),
-                    }
-                } else {
-                    Err(ParseError::Incomplete)
-                }
+        let response = reply(buf)?;
+        buffer.consume(response.bytes);
+        Ok(response)
+    }
+
+    fn decode_items(&self, buffer: &mut Session) -> Result<(Response, Vec<Item>), ParseError> {
+        let buf: &[u8] = (*buffer).buffer();
+
+        if let Some((redirect, consumed)) = redirect(buf)? {
+            buffer.consume(consumed);
+            return Err(redirect);
+        }
+
+        let response = reply(buf)?;
+        let items = items(&buf[0..response.bytes])?;
+        buffer.consume(response.bytes);
+        Ok((response, items))
+    }
+
+    /// Authenticates the session if credentials are configured, then
+    /// negotiates the protocol version, selects the database, and sets the
+    /// client name as configured.
+    fn setup(&mut self, buf: &mut Session) -> usize {
+        let mut count = 0;
+
+        if let Some(password) = self.config.auth().password() {
+            let username = self.config.auth().username();
+            let mut args: Vec<&[u8]> = Vec::new();
+            if let Some(ref username) = username {
+                args.push(username.as_bytes());
             }
-            Ok("-") => {
-                // error response
-                Err(ParseError::Error)
+            args.push(password.as_bytes());
+            Self::command(buf, &self.mode, "auth", &args);
+            count += 1;
+        }
+
+        if let Mode::Resp3 = self.mode {
+            Self::command(buf, &self.mode, "hello", &[b"3"]);
+            count += 1;
+        }
+
+        if let Some(database) = self.config.connection().database() {
+            let database = format!("{}", database);
+            Self::command(buf, &self.mode, "select", &[database.as_bytes()]);
+            count += 1;
+        }
+
+        if let Some(name) = self.config.connection().client_name() {
+            Self::command(buf, &self.mode, "client", &[b"setname", name.as_bytes()]);
+            count += 1;
+        }
+
+        count
+    }
+}
+
+/// Returns the content of the line following the type byte and the length of
+/// the line including the type byte and the CRLF.
+fn line(buf: &[u8]) -> Result<(&[u8], usize), ParseError> {
+    let mut lines = buf.windows(2);
+    if let Some(line_end) = lines.position(|w| w == b"\r\n") {
+        // every line starts with a type byte
+        if line_end == 0 {
+            return Err(ParseError::Unknown);
+        }
+        Ok((&buf[1..line_end], line_end + 2))
+    } else {
+        Err(ParseError::Incomplete)
+    }
+}
+
+fn integer(msg: &[u8]) -> Result<i64, ParseError> {
+    str::from_utf8(msg)
+        .map_err(|_| ParseError::Unknown)?
+        .parse::<i64>()
+        .map_err(|_| ParseError::Unknown)
+}
+
+/// Returns the number of elements of an aggregate. Only an array may be null,
+/// which is sent as a length of -1 and has no elements.
+fn count(msg: &[u8], nullable: bool) -> Result<u64, ParseError> {
+    match integer(msg)? {
+        -1 if nullable => Ok(0),
+        count => u64::try_from(count).map_err(|_| ParseError::Unknown),
+    }
+}
+
+/// Parses the reply to a single request. Out-of-band push messages which
+/// precede the reply are skipped and included in the returned length. The kind
+/// of response is determined by the type of the reply.
+fn reply(buf: &[u8]) -> Result<Response, ParseError> {
+    let mut offset = 0;
+    while buf.get(offset) == Some(&b'>') {
+        let mut ignored = 0;
+        offset += value(&buf[offset..], 1, &mut ignored)?;
+    }
+
+    let mut hits = 0;
+    let len = value(&buf[offset..], 0, &mut hits)?;
+    let kind = match &buf[offset..(offset + len)] {
+        [b'-' | b'!', ..] => ResponseKind::Error,
+        [b'_', ..] | [b'$' | b'*', b'-', ..] => ResponseKind::Miss,
+        [b'$' | b'=', ..] => ResponseKind::Hit,
+        [b'*' | b'~', ..] if hits > 0 => ResponseKind::Hit,
+        [b'*' | b'~', ..] => ResponseKind::Miss,
+        _ => ResponseKind::Ok,
+    };
+    Ok(Response::new(kind, hits, offset + len))
+}
+
+/// Returns the values of a complete reply to a lookup, which is either a
+/// single value or an array of values, such as the reply to `mget`. Each
+/// element which is not a bulk string is returned as a missing value.
+fn items(buf: &[u8]) -> Result<Vec<Item>, ParseError> {
+    let mut offset = 0;
+    let mut ignored = 0;
+    while buf.get(offset) == Some(&b'>') {
+        offset += value(&buf[offset..], 1, &mut ignored)?;
+    }
+
+    let count = if buf.get(offset) == Some(&b'*') {
+        let (msg, len) = line(&buf[offset..])?;
+        offset += len;
+        count(msg, true)?
+    } else {
+        1
+    };
+
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let end = offset + value(&buf[offset..], 1, &mut ignored)?;
+        let value = match header(&buf[offset..], b'$') {
+            Ok((size, len)) if size >= 0 => {
+                Some(buf[(offset + len)..(offset + len + size as usize)].to_vec())
             }
-            Ok(":") => {
-                // numeric response
-                let mut lines = buf.windows(2);
-                if let Some(line_end) = lines.position(|w| w == b"\r\n") {
-                    let msg = &buf[1..line_end];
-                    match str::from_utf8(msg) {
-                        Ok(msg) => match msg.parse::<i64>() {
-                            Ok(_) => {
-                                let _ = buffer.consume(line_end + 2);
-                                Ok(())
-                            }
-                            Err(_) => Err(ParseError::Unknown),
-                        },
-                        Err(_) => Err(ParseError::Unknown),
-                    }
-                } else {
-                    Err(ParseError::Incomplete)
-                }
+            _ => None,
+        };
+        items.push(Item { key: None, value });
+        offset = end;
+    }
+    Ok(items)
+}
+
+/// Parses a `-MOVED` or `-ASK` error reply, which redirects the request to
+/// another node of a redis cluster, and returns it along with its length.
+fn redirect(buf: &[u8]) -> Result<Option<(ParseError, usize)>, ParseError> {
+    if !(buf.starts_with(b"-MOVED ") || buf.starts_with(b"-ASK ")) {
+        return Ok(None);
+    }
+
+    let (msg, len) = line(buf)?;
+    let mut tokens = msg.split(|b| *b == b' ');
+    let kind = tokens.next().ok_or(ParseError::Unknown)?;
+    let slot = tokens
+        .next()
+        .and_then(|t| str::from_utf8(t).ok())
+        .and_then(|t| t.parse::<u16>().ok())
+        .ok_or(ParseError::Unknown)?;
+    // an endpoint which cannot be parsed, such as an unknown hostname, can not
+    // be followed
+    let endpoint = tokens
+        .next()
+        .and_then(|t| str::from_utf8(t).ok())
+        .and_then(|t| t.parse::<SocketAddr>().ok())
+        .ok_or(ParseError::Error)?;
+
+    let redirect = if kind == b"MOVED" {
+        ParseError::Moved { slot, endpoint }
+    } else {
+        ParseError::Ask { slot, endpoint }
+    };
+    Ok(Some((redirect, len)))
+}
+
+/// A range of hash slots and the node which serves them.
+#[derive(Debug, PartialEq)]
+pub struct SlotRange {
+    pub start: u16,
+    pub end: u16,
+    /// the host of the primary, which is empty if it is the node that sent the
+    /// reply
+    pub host: Vec<u8>,
+    pub port: u16,
+}
+
+/// Parses the reply to `CLUSTER SLOTS`, returning the slot ranges and the
+/// length of the reply. Replicas of each range are ignored.
+pub fn cluster_slots(buf: &[u8]) -> Result<(Vec<SlotRange>, usize), ParseError> {
+    let (count, mut offset) = header(buf, b'*')?;
+    let mut ranges = Vec::new();
+    for _ in 0..count {
+        let (fields, len) = header(&buf[offset..], b'*')?;
+        offset += len;
+        if fields < 3 {
+            return Err(ParseError::Unknown);
+        }
+        let (start, len) = header(&buf[offset..], b':')?;
+        offset += len;
+        let (end, len) = header(&buf[offset..], b':')?;
+        offset += len;
+
+        // the primary is an array of the host, port, and additional details
+        let (details, len) = header(&buf[offset..], b'*')?;
+        offset += len;
+        if details < 2 {
+            return Err(ParseError::Unknown);
+        }
+        let (size, len) = header(&buf[offset..], b'$')?;
+        let host_end = blob(&buf[offset..], len, size)?;
+        let host = buf[(offset + len)..(offset + host_end - 2)].to_vec();
+        offset += host_end;
+        let (port, len) = header(&buf[offset..], b':')?;
+        offset += len;
+
+        let mut ignored = 0;
+        for _ in 2..details {
+            offset += value(&buf[offset..], 1, &mut ignored)?;
+        }
+        for _ in 3..fields {
+            offset += value(&buf[offset..], 1, &mut ignored)?;
+        }
+
+        ranges.push(SlotRange {
+            start: u16::try_from(start).map_err(|_| ParseError::Unknown)?,
+            end: u16::try_from(end).map_err(|_| ParseError::Unknown)?,
+            host,
+            port: u16::try_from(port).map_err(|_| ParseError::Unknown)?,
+        });
+    }
+    Ok((ranges, offset))
+}
+
+/// Parses a line with the expected type byte and an integer, such as an
+/// integer reply or the length of an array, returning the integer and the
+/// length of the line.
+fn header(buf: &[u8], prefix: u8) -> Result<(i64, usize), ParseError> {
+    if buf.is_empty() {
+        return Err(ParseError::Incomplete);
+    }
+    let (msg, len) = line(buf)?;
+    if buf[0] != prefix {
+        return Err(ParseError::Unknown);
+    }
+    Ok((integer(msg)?, len))
+}
+
+/// Returns the end of a length prefixed blob which follows a line of the
+/// provided length. A negative or overflowing size can not be parsed.
+fn blob(buf: &[u8], len: usize, size: i64) -> Result<usize, ParseError> {
+    let end = usize::try_from(size)
+        .ok()
+        .and_then(|size| len.checked_add(size))
+        .and_then(|end| end.checked_add(2))
+        .ok_or(ParseError::Unknown)?;
+    if buf.len() < end {
+        return Err(ParseError::Incomplete);
+    }
+    if &buf[(end - 2)..end] != b"\r\n" {
+        return Err(ParseError::Unknown);
+    }
+    Ok(end)
+}
+
+/// Parses a single RESP value from the start of the buffer and returns the
+/// number of bytes it occupies. Arrays are parsed recursively. Non-null bulk
+/// strings are counted as hits when they are the reply itself or an element
+/// of the top-level array, which gives per-key hits for `mget`. The RESP3
+/// types are also understood, the elements of maps and attributes are never
+/// counted as hits.
+fn value(buf: &[u8], depth: usize, hits: &mut usize) -> Result<usize, ParseError> {
+    if buf.is_empty() {
+        return Err(ParseError::Incomplete);
+    }
+
+    let (msg, len) = line(buf)?;
+
+    match buf[0] {
+        b'+' => {
+            // simple string
+            Ok(len)
+        }
+        b'-' => {
+            // error
+            Ok(len)
+        }
+        b':' => {
+            // integer
+            integer(msg)?;
+            Ok(len)
+        }
+        b'$' => {
+            // bulk string, a length of -1 is the null bulk string
+            let size = integer(msg)?;
+            if size == -1 {
+                return Ok(len);
             }
-            Ok("$") => {
-                // bulk string
-                let mut lines = buf.windows(2);
-                if let Some(line_end) = lines.position(|w| w == b"\r\n") {
-                    let msg = &buf[1..line_end];
-                    match str::from_utf8(msg) {
-                        Ok("-1") => {
-                            let _ = buffer.consume(line_end + 2);
-                            Ok(())
-                        }
-                        Ok(n) => {
-                            let len = n.parse::<usize>().map_err(|_| ParseError::Unknown)?;
-                            let response_end = len + line_end + 4;
-                            if response_end <= buf.len() {
-                                metrics::RESPONSE_HIT.increment();
-                                let _ = buffer.consume(response_end);
-                                Ok(())
-                            } else {
-                                Err(ParseError::Incomplete)
-                            }
-                        }
-                        Err(_) => Err(ParseError::Unknown),
-                    }
-                } else {
-                    Err(ParseError::Incomplete)
-                }
+            let end = blob(buf, len, size)?;
+            if depth <= 1 {
+                *hits += 1;
             }
-            Ok("*") => {
-                // arrays
-                let msg = &buf[1..buf.len() - 2];
-                match str::from_utf8(msg) {
-                    Ok("-1") => {
-                        let response_end = buf.len();
-                        let _ = buffer.consume(response_end);
-                        Ok(())
-                    }
-                    Ok(_) => {
-                        // TODO: implement array parsing
-                        Err(ParseError::Unknown)
-                    }
-                    Err(_) => Err(ParseError::Unknown),
-                }
+            Ok(end)
+        }
+        b'*' | b'~' | b'>' => {
+            // array, set, or push, only an array may be null
+            let count = count(msg, buf[0] == b'*')?;
+            let mut end = len;
+            for _ in 0..count {
+                end += value(&buf[end..], depth + 1, hits)?;
+            }
+            Ok(end)
+        }
+        b'_' => {
+            // null
+            if !msg.is_empty() {
+                return Err(ParseError::Unknown);
+            }
+            Ok(len)
+        }
+        b',' => {
+            // double
+            str::from_utf8(msg)
+                .map_err(|_| ParseError::Unknown)?
+                .parse::<f64>()
+                .map_err(|_| ParseError::Unknown)?;
+            Ok(len)
+        }
+        b'#' => {
+            // boolean
+            match msg {
+                b"t" | b"f" => Ok(len),
+                _ => Err(ParseError::Unknown),
+            }
+        }
+        b'(' => {
+            // big number
+            let digits = msg.strip_prefix(b"-").unwrap_or(msg);
+            if digits.is_empty() || !digits.iter().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::Unknown);
+            }
+            Ok(len)
+        }
+        b'!' => {
+            // blob error
+            let size = integer(msg)?;
+            blob(buf, len, size)
+        }
+        b'=' => {
+            // verbatim string, the content is prefixed by the format
+            let size = integer(msg)?;
+            let end = blob(buf, len, size)?;
+            if depth <= 1 {
+                *hits += 1;
+            }
+            Ok(end)
+        }
+        b'%' => {
+            // map
+            let count = 2 * count(msg, false)?;
+            let mut ignored = 0;
+            let mut end = len;
+            for _ in 0..count {
+                end += value(&buf[end..], depth + 1, &mut ignored)?;
+            }
+            Ok(end)
+        }
+        b'|' => {
+            // attribute, which is followed by the value it describes
+            let count = 2 * count(msg, false)?;
+            let mut ignored = 0;
+            let mut end = len;
+            for _ in 0..count {
+                end += value(&buf[end..], depth + 1, &mut ignored)?;
             }
-            _ => Err(ParseError::Unknown),
+            end += value(&buf[end..], depth, hits)?;
+            Ok(end)
         }
+        _ => Err(ParseError::Unknown),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn decode(buf: &[u8]) -> Result<(usize, usize), ParseError> {
+        reply(buf).map(|response| (response.bytes, response.items))
+    }
+
+    fn kind(buf: &[u8]) -> ResponseKind {
+        reply(buf).unwrap().kind
+    }
+
+    #[test]
+    fn simple() {
+        assert_eq!(decode(b""), Err(ParseError::Incomplete));
+        assert_eq!(decode(b"+OK\r"), Err(ParseError::Incomplete));
+        assert_eq!(decode(b"+OK\r\n"), Ok((5, 0)));
+        assert_eq!(decode(b":42\r\n"), Ok((5, 0)));
+        assert_eq!(decode(b":abc\r\n"), Err(ParseError::Unknown));
+        assert_eq!(decode(b"-ERR bad\r\n"), Ok((10, 0)));
+    }
+
+    #[test]
+    fn bulk_string() {
+        assert_eq!(decode(b"$-1\r\n"), Ok((5, 0)));
+        assert_eq!(decode(b"$5\r\nhel"), Err(ParseError::Incomplete));
+        assert_eq!(decode(b"$5\r\nhello\r\n"), Ok((11, 1)));
+        assert_eq!(decode(b"$0\r\n\r\n"), Ok((6, 1)));
+
+        // lengths which are not valid
+        assert_eq!(decode(b"$-2\r\n"), Err(ParseError::Unknown));
+        assert_eq!(decode(b"!-5\r\n"), Err(ParseError::Unknown));
+        assert_eq!(
+            decode(b"$9223372036854775807\r\nabc\r\n"),
+            Err(ParseError::Incomplete)
+        );
+    }
+
+    #[test]
+    fn array() {
+        assert_eq!(decode(b"*-1\r\n"), Ok((5, 0)));
+        assert_eq!(decode(b"*0\r\n"), Ok((4, 0)));
+
+        // mget with one miss
+        let buf = b"*3\r\n$1\r\na\r\n$-1\r\n$1\r\nc\r\n";
+        assert_eq!(
+            decode(&buf[0..(buf.len() - 1)]),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(decode(buf), Ok((buf.len(), 2)));
+
+        let values: Vec<Option<Vec<u8>>> = items(buf)
+            .unwrap()
+            .into_iter()
+            .map(|item| item.value)
+            .collect();
+        assert_eq!(values, [Some(b"a".to_vec()), None, Some(b"c".to_vec())]);
+
+        // nested arrays only count hits for the outer elements
+        let buf = b"*2\r\n*2\r\n$1\r\na\r\n:1\r\n-ERR nested\r\n";
+        assert_eq!(decode(buf), Ok((buf.len(), 0)));
+
+        // only -1 is the null array
+        assert_eq!(decode(b"*-2\r\n"), Err(ParseError::Unknown));
+        assert!(items(b"*-5\r\n").is_err());
+    }
+
+    #[test]
+    fn empty_line() {
+        assert_eq!(decode(b"\r\n"), Err(ParseError::Unknown));
+        assert_eq!(decode(b"*1\r\n\r\n"), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn resp3() {
+        assert_eq!(decode(b"_\r\n"), Ok((3, 0)));
+        assert_eq!(decode(b",3.14\r\n"), Ok((7, 0)));
+        assert_eq!(decode(b",inf\r\n"), Ok((6, 0)));
+        assert_eq!(decode(b"#t\r\n"), Ok((4, 0)));
+        assert_eq!(decode(b"#x\r\n"), Err(ParseError::Unknown));
+        assert_eq!(
+            decode(b"(-3492890328409238509324850943850943825024385\r\n"),
+            Ok((47, 0))
+        );
+        assert_eq!(decode(b"!5\r\nERROR\r\n"), Ok((11, 0)));
+        assert_eq!(decode(b"=9\r\ntxt:hello\r\n"), Ok((15, 1)));
+        assert_eq!(decode(b"~2\r\n$1\r\na\r\n:1\r\n"), Ok((15, 1)));
+
+        // the reply to `HELLO 3`
+        let buf = b"%2\r\n$6\r\nserver\r\n$5\r\nredis\r\n$5\r\nproto\r\n:3\r\n";
+        assert_eq!(decode(&buf[0..20]), Err(ParseError::Incomplete));
+        assert_eq!(decode(buf), Ok((buf.len(), 0)));
+
+        // an attribute is parsed along with the value it describes
+        let buf = b"|1\r\n+ttl\r\n:3600\r\n$1\r\na\r\n";
+        assert_eq!(decode(buf), Ok((buf.len(), 1)));
+
+        // push messages before a reply are skipped
+        let buf = b">2\r\n$7\r\nmessage\r\n$1\r\nx\r\n$1\r\na\r\n";
+        assert_eq!(decode(buf), Ok((buf.len(), 1)));
+
+        // sets, pushes, maps, and attributes have no null form
+        assert_eq!(decode(b"~-1\r\n"), Err(ParseError::Unknown));
+        assert_eq!(decode(b">-1\r\n$1\r\na\r\n"), Err(ParseError::Unknown));
+        assert_eq!(decode(b"%-1\r\n"), Err(ParseError::Unknown));
+        assert_eq!(decode(b"|-1\r\n$1\r\na\r\n"), Err(ParseError::Unknown));
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(kind(b"+OK\r\n"), ResponseKind::Ok);
+        assert_eq!(kind(b":1\r\n"), ResponseKind::Ok);
+        assert_eq!(kind(b"-ERR bad\r\n"), ResponseKind::Error);
+        assert_eq!(kind(b"!5\r\nERROR\r\n"), ResponseKind::Error);
+        assert_eq!(kind(b"$1\r\na\r\n"), ResponseKind::Hit);
+        assert_eq!(kind(b"$-1\r\n"), ResponseKind::Miss);
+        assert_eq!(kind(b"_\r\n"), ResponseKind::Miss);
+        assert_eq!(kind(b"*-1\r\n"), ResponseKind::Miss);
+        assert_eq!(kind(b"*2\r\n$-1\r\n$-1\r\n"), ResponseKind::Miss);
+        assert_eq!(kind(b"*2\r\n$-1\r\n$1\r\nb\r\n"), ResponseKind::Hit);
+
+        // the kind is taken from the reply which follows any push messages
+        let buf = b">2\r\n$7\r\nmessage\r\n$1\r\nx\r\n-ERR bad\r\n";
+        assert_eq!(kind(buf), ResponseKind::Error);
+    }
+
+    #[test]
+    fn redirect() {
+        let buf = b"-MOVED 3999 127.0.0.1:6381\r\n+OK\r\n";
+        assert_eq!(
+            super::redirect(buf),
+            Ok(Some((
+                ParseError::Moved {
+                    slot: 3999,
+                    endpoint: "127.0.0.1:6381".parse().unwrap()
+                },
+                28
+            )))
+        );
+        assert_eq!(
+            super::redirect(b"-ASK 3999 127.0.0.1:6381\r\n"),
+            Ok(Some((
+                ParseError::Ask {
+                    slot: 3999,
+                    endpoint: "127.0.0.1:6381".parse().unwrap()
+                },
+                26
+            )))
+        );
+        assert_eq!(
+            super::redirect(b"-MOVED 3999 127.0"),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(super::redirect(b"-ERR moved\r\n"), Ok(None));
+    }
+
+    #[test]
+    fn slots() {
+        let buf = b"*2\r\n\
+            *3\r\n:0\r\n:5460\r\n*3\r\n$9\r\n127.0.0.1\r\n:30001\r\n$2\r\nid\r\n\
+            *4\r\n:5461\r\n:16383\r\n*2\r\n$0\r\n\r\n:30002\r\n*2\r\n$9\r\n127.0.0.1\r\n:30004\r\n";
+        assert_eq!(
+            cluster_slots(&buf[0..40]).map(|(_, len)| len),
+            Err(ParseError::Incomplete)
+        );
+        assert_eq!(
+            cluster_slots(buf),
+            Ok((
+                vec![
+                    SlotRange {
+                        start: 0,
+                        end: 5460,
+                        host: b"127.0.0.1".to_vec(),
+                        port: 30001
+                    },
+                    SlotRange {
+                        start: 5461,
+                        end: 16383,
+                        host: Vec::new(),
+                        port: 30002
+                    },
+                ],
+                buf.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn pipelined() {
+        let buf = b"$1\r\na\r\n+OK\r\n";
+        assert_eq!(decode(buf), Ok((7, 1)));
+        assert_eq!(decode(&buf[7..]), Ok((5, 0)));
     }
 }
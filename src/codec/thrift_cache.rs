@@ -6,35 +6,19 @@ use super::thrift;
 use std::io::BufRead;
 
 use crate::codec::*;
-use crate::config::*;
-use crate::config_file::Verb;
 use crate::*;
 
 use std::io::Write;
 
-use rand::rngs::SmallRng;
-use rand::SeedableRng;
-
-pub struct ThriftCache {
-    config: Arc<Config>,
-    rng: SmallRng,
-}
+#[derive(Default)]
+pub struct ThriftCache;
 
 impl ThriftCache {
-    pub fn new(config: Arc<Config>) -> Self {
-        Self {
-            config,
-            rng: SmallRng::from_entropy(),
-        }
+    pub fn new() -> Self {
+        Self
     }
 
-    fn append(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let mut values = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
-        }
-
+    fn append(key: &[u8], values: &[Vec<u8>], buf: &mut Session) {
         let mut buffer = thrift::ThriftBuffer::new();
         buffer.protocol_header();
         buffer.method_name("append");
@@ -53,7 +37,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         buffer.write_bytes(&[thrift::LIST]);
         buffer.write_i16(3);
@@ -62,7 +46,7 @@ impl ThriftCache {
 
         for value in values {
             buffer.write_i32(value.len() as i32);
-            buffer.write_bytes(&value);
+            buffer.write_bytes(value);
         }
 
         // stop request struct
@@ -74,13 +58,7 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn appendx(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let mut values = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
-        }
-
+    fn appendx(key: &[u8], values: &[Vec<u8>], buf: &mut Session) {
         let mut buffer = thrift::ThriftBuffer::new();
         buffer.protocol_header();
         buffer.method_name("appendx");
@@ -99,7 +77,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         buffer.write_bytes(&[thrift::LIST]);
         buffer.write_i16(3);
@@ -108,7 +86,7 @@ impl ThriftCache {
 
         for value in values {
             buffer.write_i32(value.len() as i32);
-            buffer.write_bytes(&value);
+            buffer.write_bytes(value);
         }
 
         // stop request struct
@@ -120,8 +98,7 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn count(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn count(key: &[u8], buf: &mut Session) {
         let timeout = None;
 
         let mut buffer = thrift::ThriftBuffer::new();
@@ -142,7 +119,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         if let Some(timeout) = timeout {
             buffer.write_bytes(&[thrift::I32]);
@@ -159,16 +136,7 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn get(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let mut fields = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            fields.push(
-                keyspace
-                    .generate_inner_key(rng)
-                    .unwrap_or_else(|| b"".to_vec()),
-            );
-        }
+    fn get(key: &[u8], fields: &[Vec<u8>], buf: &mut Session) {
         let timeout = None;
 
         let mut buffer = thrift::ThriftBuffer::new();
@@ -189,7 +157,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         buffer.write_bytes(&[thrift::LIST]);
         buffer.write_i16(3);
@@ -198,7 +166,7 @@ impl ThriftCache {
 
         for field in fields {
             buffer.write_i32(field.len() as i32);
-            buffer.write_bytes(&field);
+            buffer.write_bytes(field);
         }
 
         if let Some(timeout) = timeout {
@@ -216,23 +184,9 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn put(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let mut fields = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            fields.push(
-                keyspace
-                    .generate_inner_key(rng)
-                    .unwrap_or_else(|| b"".to_vec()),
-            );
-        }
-        let mut values = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
-        }
+    fn put(key: &[u8], fields: &[Vec<u8>], values: &[Vec<u8>], ttl: u32, buf: &mut Session) {
         let timeout = None;
         let timestamp = None;
-        let ttl = keyspace.ttl();
 
         let mut buffer = thrift::ThriftBuffer::new();
         buffer.protocol_header();
@@ -252,7 +206,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         buffer.write_bytes(&[thrift::LIST]);
         buffer.write_i16(3);
@@ -261,7 +215,7 @@ impl ThriftCache {
 
         for field in fields {
             buffer.write_i32(field.len() as i32);
-            buffer.write_bytes(&field);
+            buffer.write_bytes(field);
         }
 
         buffer.write_bytes(&[thrift::LIST]);
@@ -271,7 +225,7 @@ impl ThriftCache {
 
         for value in values {
             buffer.write_i32(value.len() as i32);
-            buffer.write_bytes(&value);
+            buffer.write_bytes(value);
         }
 
         if let Some(timestamp) = timestamp {
@@ -301,16 +255,7 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn remove(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let mut fields = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            fields.push(
-                keyspace
-                    .generate_inner_key(rng)
-                    .unwrap_or_else(|| b"".to_vec()),
-            );
-        }
+    fn remove(key: &[u8], fields: &[Vec<u8>], buf: &mut Session) {
         let timeout = None;
         let timestamp = None;
         let count = None;
@@ -333,7 +278,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         buffer.write_bytes(&[thrift::LIST]);
         buffer.write_i16(3);
@@ -342,7 +287,7 @@ impl ThriftCache {
 
         for field in fields {
             buffer.write_i32(field.len() as i32);
-            buffer.write_bytes(&field);
+            buffer.write_bytes(field);
         }
 
         if let Some(timestamp) = timestamp {
@@ -372,20 +317,7 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn range(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
-        let mut fields = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            fields.push(
-                keyspace
-                    .generate_inner_key(rng)
-                    .unwrap_or_else(|| b"".to_vec()),
-            );
-        }
-        let mut values = Vec::new();
-        for _ in 0..keyspace.batch_size() {
-            values.push(keyspace.generate_value(rng).unwrap_or_else(|| b"".to_vec()));
-        }
+    fn range(key: &[u8], buf: &mut Session) {
         let start = None;
         let stop = None;
 
@@ -407,7 +339,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         if let Some(start) = start {
             buffer.write_bytes(&[thrift::I32]);
@@ -431,8 +363,7 @@ impl ThriftCache {
     }
 
     #[allow(dead_code)]
-    fn scan(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn scan(key: &[u8], buf: &mut Session) {
         let start_field = None;
         let end_field = None;
         let ascending = None;
@@ -457,7 +388,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         if let Some(start_field) = start_field {
             buffer.write_bytes(&[thrift::STRING]);
@@ -498,8 +429,7 @@ impl ThriftCache {
         let _ = buf.write(buffer.as_bytes());
     }
 
-    fn trim(rng: &mut SmallRng, keyspace: &Keyspace, buf: &mut Session) {
-        let key = keyspace.generate_key(rng);
+    fn trim(key: &[u8], buf: &mut Session) {
         let target_size = 1;
         let trim_from_smallest = true;
         let timeout = None;
@@ -522,7 +452,7 @@ impl ThriftCache {
         buffer.write_bytes(&[thrift::STRING]);
         buffer.write_i16(2);
         buffer.write_i32(key.len() as i32);
-        buffer.write_bytes(&key);
+        buffer.write_bytes(key);
 
         buffer.write_bytes(&[thrift::I32]);
         buffer.write_i16(3);
@@ -549,25 +479,28 @@ impl ThriftCache {
 }
 
 impl Codec for ThriftCache {
-    fn encode(&mut self, buf: &mut Session) {
-        let keyspace = self.config.choose_keyspace(&mut self.rng);
-        let command = keyspace.choose_command(&mut self.rng);
-        match command.verb() {
-            Verb::Rpush => Self::append(&mut self.rng, keyspace, buf),
-            Verb::Rpushx => Self::appendx(&mut self.rng, keyspace, buf),
-            Verb::Count => Self::count(&mut self.rng, keyspace, buf),
-            Verb::Hget => Self::get(&mut self.rng, keyspace, buf),
-            Verb::Hset => Self::put(&mut self.rng, keyspace, buf),
-            Verb::Hdel => Self::remove(&mut self.rng, keyspace, buf),
-            Verb::Lrange => Self::range(&mut self.rng, keyspace, buf),
-            Verb::Ltrim => Self::trim(&mut self.rng, keyspace, buf),
+    fn encode(&mut self, buf: &mut Session, request: &Request) {
+        match request {
+            Request::Rpush { key, values } => Self::append(key, values, buf),
+            Request::Rpushx { key, values } => Self::appendx(key, values, buf),
+            Request::Count { key } => Self::count(key, buf),
+            Request::Hget { key, fields } => Self::get(key, fields, buf),
+            Request::Hset {
+                key,
+                fields,
+                values,
+                ttl,
+            } => Self::put(key, fields, values, *ttl, buf),
+            Request::Hdel { key, fields } => Self::remove(key, fields, buf),
+            Request::Lrange { key } => Self::range(key, buf),
+            Request::Ltrim { key } => Self::trim(key, buf),
             _ => {
                 unimplemented!()
             }
         }
     }
 
-    fn decode(&self, buffer: &mut Session) -> Result<(), ParseError> {
+    fn decode(&self, buffer: &mut Session) -> Result<Response, ParseError> {
         // no-copy borrow as a slice
         let buf: &[u8] = (*buffer).buffer();
 
@@ -579,7 +512,7 @@ impl Codec for ThriftCache {
                 Some(b) => {
                     if b == bytes {
                         buffer.consume(b as usize);
-                        Ok(())
+                        Ok(Response::new(ResponseKind::Ok, 0, b as usize))
                     } else {
                         Err(ParseError::Incomplete)
                     }
@@ -3,8 +3,9 @@
 // http://www.apache.org/licenses/LICENSE-2.0
 
 use crate::config_file::*;
+use crate::routing::Router;
 use rand::rngs::SmallRng;
-use rand::Rng;
+use rand::{Rng, SeedableRng};
 use rand_distr::Alphanumeric;
 use rand_distr::Uniform;
 use rand_distr::{Distribution, WeightedAliasIndex};
@@ -18,12 +19,26 @@ pub struct Config {
     general: General,
     debug: Debug,
     waterfall: Waterfall,
+    trace: Trace,
     connection: Connection,
     request: Request,
     tls: Option<Tls>,
+    auth: Auth,
     endpoints: Vec<SocketAddr>,
+    routing: Option<Routing>,
+    router: Option<Router>,
     keyspaces: Vec<Keyspace>,
-    keyspace_dist: WeightedAliasIndex<usize>,
+    keyspace_dist: Option<WeightedAliasIndex<usize>>,
+    seed: u64,
+}
+
+/// The independent random streams used by each worker.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    /// generates the requests
+    Requests = 0,
+    /// shuffles the order in which connections are opened
+    Connect = 1,
 }
 
 #[derive(Clone)]
@@ -43,6 +58,7 @@ impl KeyDistribution {
 
 #[derive(Clone)]
 pub struct Keyspace {
+    index: usize,
     length: usize,
     weight: usize,
     cardinality: u32,
@@ -55,10 +71,16 @@ pub struct Keyspace {
     ttl: usize,
     key_type: FieldType,
     batch_size: usize,
+    batch_fields: bool,
     key_distribution: KeyDistribution,
 }
 
 impl Keyspace {
+    /// The position of the keyspace within the config.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
     pub fn length(&self) -> usize {
         self.length
     }
@@ -132,6 +154,10 @@ impl Keyspace {
         }
     }
 
+    pub fn commands(&self) -> &[Command] {
+        &self.commands
+    }
+
     pub fn choose_command(&self, rng: &mut SmallRng) -> &Command {
         &self.commands[self.command_dist.sample(rng)]
     }
@@ -151,6 +177,10 @@ impl Keyspace {
     pub fn batch_size(&self) -> usize {
         self.batch_size
     }
+
+    pub fn batch_fields(&self) -> bool {
+        self.batch_fields
+    }
 }
 
 impl Config {
@@ -161,6 +191,21 @@ impl Config {
             fatal!("need a config file");
         };
 
+        if config_file.keyspaces().is_empty() {
+            fatal!("no keyspaces configured");
+        }
+
+        Self::from_config_file(config_file)
+    }
+
+    /// Creates the config from a toml document. This is used by `rpc-replay`,
+    /// which builds its config from the command line. Keyspaces are optional
+    /// as the requests are read from a trace instead of being generated.
+    pub fn from_toml(toml: toml::Value) -> Self {
+        Self::from_config_file(ConfigFile::from_toml(toml))
+    }
+
+    fn from_config_file(config_file: ConfigFile) -> Self {
         let mut keyspaces = Vec::new();
         for k in config_file.keyspaces() {
             let inner_keys = k.inner_keys();
@@ -212,6 +257,7 @@ impl Config {
             };
 
             let keyspace = Keyspace {
+                index: keyspaces.len(),
                 length: k.length(),
                 weight: k.weight(),
                 cardinality: k.cardinality(),
@@ -224,28 +270,120 @@ impl Config {
                 ttl: k.ttl(),
                 key_type: k.key_type(),
                 batch_size: k.batch_size(),
+                batch_fields: k.batch_fields(),
                 key_distribution,
             };
             keyspaces.push(keyspace);
         }
 
-        let weights: Vec<usize> = keyspaces.iter().map(|k| k.weight).collect();
-        let keyspace_dist = WeightedAliasIndex::new(weights).unwrap();
+        let keyspace_dist = if keyspaces.is_empty() {
+            None
+        } else {
+            let weights: Vec<usize> = keyspaces.iter().map(|k| k.weight).collect();
+            Some(WeightedAliasIndex::new(weights).unwrap())
+        };
 
-        if config_file.target().endpoints().is_empty() {
+        let endpoints = config_file.target().endpoints();
+        if endpoints.is_empty() {
             fatal!("no target endpoints configured");
         }
 
+        if config_file.target().routing().is_some() {
+            if let Protocol::Ping | Protocol::Echo = config_file.general().protocol() {
+                fatal!("routing requires a protocol which uses keys");
+            }
+        }
+
+        if config_file.target().routing() == Some(Routing::RedisCluster) {
+            match config_file.general().protocol() {
+                Protocol::RedisResp | Protocol::RedisResp3 => {}
+                protocol => {
+                    fatal!("protocol: {:?} cannot be used with redis cluster", protocol);
+                }
+            }
+            // the keys of a multi-get would need to share a hash slot
+            if keyspaces.iter().any(|k| k.batch_size() > 1) {
+                fatal!("batch_size must be 1 when using redis cluster");
+            }
+        }
+
+        // the meta and binary protocol flags apply to every request for the
+        // verb
+        if let Protocol::MemcacheMeta | Protocol::MemcacheBinary = config_file.general().protocol()
+        {
+            let commands: Vec<Command> = keyspaces
+                .iter()
+                .flat_map(|k| k.commands().iter().cloned())
+                .collect();
+            for command in &commands {
+                if commands
+                    .iter()
+                    .any(|c| c.verb() == command.verb() && c.flags() != command.flags())
+                {
+                    fatal!(
+                        "verb: {:?} must use the same flags in every keyspace",
+                        command.verb()
+                    );
+                }
+            }
+        }
+
+        // a quiet meta command only has a response on failure, which would
+        // leave the request waiting until it times out. multi-gets are sent
+        // as quiet gets by the codec, which terminates them with a no-op
+        if config_file.general().protocol() == Protocol::MemcacheMeta {
+            for keyspace in &keyspaces {
+                for command in keyspace.commands() {
+                    let quiet = command
+                        .flags()
+                        .map(|flags| flags.split_whitespace().any(|flag| flag == "q"))
+                        .unwrap_or(false);
+                    if quiet {
+                        fatal!(
+                            "verb: {:?} cannot use the quiet flag with the memcache meta protocol",
+                            command.verb()
+                        );
+                    }
+                }
+            }
+        }
+
+        if config_file.request().open_loop() && config_file.request().ratelimit().is_none() {
+            fatal!("open-loop mode requires a request ratelimit");
+        }
+
+        if config_file.request().verify() {
+            match config_file.general().protocol() {
+                Protocol::Memcache
+                | Protocol::Redis
+                | Protocol::RedisInline
+                | Protocol::RedisResp
+                | Protocol::RedisResp3 => {}
+                protocol => {
+                    fatal!("protocol: {:?} does not support verification", protocol);
+                }
+            }
+        }
+
         Self {
             general: config_file.general(),
             debug: config_file.debug(),
             waterfall: config_file.waterfall(),
+            trace: config_file.trace(),
             tls: config_file.tls(),
+            auth: config_file.auth(),
             connection: config_file.connection(),
             request: config_file.request(),
-            endpoints: config_file.target().endpoints(),
+            router: config_file
+                .target()
+                .routing()
+                .and_then(|routing| Router::new(routing, endpoints.clone())),
+            endpoints,
+            routing: config_file.target().routing(),
             keyspaces,
             keyspace_dist,
+            // without a seed, one is chosen so that the run can be reproduced
+            seed: config_file.general().seed().unwrap_or_else(rand::random),
         }
     }
 
@@ -261,10 +399,18 @@ impl Config {
         &self.waterfall
     }
 
+    pub fn trace(&self) -> &Trace {
+        &self.trace
+    }
+
     pub fn tls(&self) -> Option<&Tls> {
         self.tls.as_ref()
     }
 
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
     pub fn connection(&self) -> &Connection {
         &self.connection
     }
@@ -277,7 +423,48 @@ impl Config {
         self.endpoints.clone()
     }
 
+    pub fn routing(&self) -> Option<Routing> {
+        self.routing
+    }
+
+    /// Returns the index of the endpoint which requests for the key are sent
+    /// to, or `None` if requests are not routed by key to a fixed list of
+    /// endpoints.
+    pub fn route(&self, key: &[u8]) -> Option<usize> {
+        let endpoint = self.router.as_ref()?.route(key);
+        self.endpoints.iter().position(|e| *e == endpoint)
+    }
+
+    pub fn keyspaces(&self) -> &[Keyspace] {
+        &self.keyspaces
+    }
+
     pub fn choose_keyspace(&self, rng: &mut SmallRng) -> &Keyspace {
-        &self.keyspaces[self.keyspace_dist.sample(rng)]
+        let keyspace_dist = self
+            .keyspace_dist
+            .as_ref()
+            .expect("no keyspaces configured");
+        &self.keyspaces[keyspace_dist.sample(rng)]
     }
+
+    /// The seed which all of the random streams are derived from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Returns the random stream for a worker. Each stream is derived from the
+    /// seed, the worker, and the purpose of the stream so that the streams are
+    /// independent of each other and identical between runs with the same seed.
+    pub fn rng(&self, worker: usize, stream: Stream) -> SmallRng {
+        let seed = mix(mix(self.seed ^ mix(worker as u64)) ^ stream as u64);
+        SmallRng::seed_from_u64(seed)
+    }
+}
+
+/// The splitmix64 finalizer, which maps similar inputs to unrelated outputs.
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
 }
@@ -11,6 +11,7 @@ use std::collections::HashMap;
 use std::io::Read;
 use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use strum_macros::AsRefStr;
 use zookeeper::*;
 
 #[derive(Deserialize)]
@@ -21,12 +22,17 @@ pub struct ConfigFile {
     debug: Debug,
     #[serde(default)]
     waterfall: Waterfall,
+    #[serde(default)]
+    trace: Trace,
     target: Target,
     #[serde(default)]
     connection: Connection,
     #[serde(default)]
     request: Request,
     tls: Option<Tls>,
+    #[serde(default)]
+    auth: Auth,
+    #[serde(default)]
     keyspace: Vec<Keyspace>,
 }
 
@@ -40,7 +46,7 @@ impl ConfigFile {
     }
 
     pub fn connection(&self) -> Connection {
-        self.connection
+        self.connection.clone()
     }
 
     pub fn request(&self) -> Request {
@@ -51,6 +57,10 @@ impl ConfigFile {
         self.tls.clone()
     }
 
+    pub fn auth(&self) -> Auth {
+        self.auth.clone()
+    }
+
     pub fn keyspaces(&self) -> Vec<Keyspace> {
         self.keyspace.clone()
     }
@@ -63,6 +73,10 @@ impl ConfigFile {
         self.waterfall.clone()
     }
 
+    pub fn trace(&self) -> Trace {
+        self.trace.clone()
+    }
+
     pub fn load_from_file(filename: &str) -> Self {
         let mut file = match std::fs::File::open(filename) {
             Ok(c) => c,
@@ -88,6 +102,17 @@ impl ConfigFile {
             }
         }
     }
+
+    /// Builds the config from a toml document which was not read from a file.
+    pub fn from_toml(toml: toml::Value) -> Self {
+        match toml.try_into() {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("invalid config\n{error}");
+                std::process::exit(1);
+            }
+        }
+    }
 }
 
 fn default_interval() -> usize {
@@ -137,12 +162,34 @@ pub enum Protocol {
     Ping,
     Echo,
     Memcache,
+    MemcacheBinary,
+    MemcacheMeta,
     Redis,
     RedisInline,
     RedisResp,
+    RedisResp3,
     ThriftCache,
 }
 
+/// Controls how requests are routed to the endpoints. By default, requests
+/// are sent over any ready session regardless of the key. Routed requests are
+/// sent in order, so a request for an endpoint without a ready session holds
+/// back the requests behind it. Once they have waited for longer than the
+/// request timeout, or one second if there is none, such requests are dropped
+/// and counted as unrouted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum Routing {
+    /// Keys are routed using ketama consistent hashing.
+    Ketama,
+    /// Keys are routed using jump consistent hashing.
+    Jump,
+    /// The endpoints are seed nodes of a redis cluster. Keys are routed by
+    /// hash slot to the node which serves the slot.
+    RedisCluster,
+}
+
 #[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct General {
@@ -156,6 +203,15 @@ pub struct General {
     #[serde(default)]
     service: bool,
     admin: Option<String>,
+    /// Seeds the random number generators. A run with the same config and
+    /// seed sends the same sequence of requests from each worker thread.
+    seed: Option<u64>,
+    /// Records a response latency heatmap for each verb and keyspace. Each
+    /// heatmap takes a few megabytes. Without it, latency percentiles are not
+    /// reported for each verb and keyspace. The latency of each endpoint is
+    /// always recorded so that outliers can be detected.
+    #[serde(default)]
+    latency_breakdown: bool,
 }
 
 impl General {
@@ -182,6 +238,14 @@ impl General {
     pub fn admin(&self) -> Option<String> {
         self.admin.clone()
     }
+
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    pub fn latency_breakdown(&self) -> bool {
+        self.latency_breakdown
+    }
 }
 
 fn log_level() -> Level {
@@ -278,7 +342,7 @@ pub enum RatelimitModel {
     Normal,
 }
 
-#[derive(Deserialize, Copy, Clone)]
+#[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Connection {
     #[serde(default = "one")]
@@ -288,10 +352,11 @@ pub struct Connection {
     reconnect: Option<usize>,
     #[serde(default = "default_nodelay")]
     tcp_nodelay: bool,
-    #[allow(dead_code)]
     timeout: Option<usize>,
     #[serde(default = "one")]
     pipeline: usize,
+    database: Option<u64>,
+    client_name: Option<String>,
 }
 
 impl Default for Connection {
@@ -304,6 +369,8 @@ impl Default for Connection {
             tcp_nodelay: false,
             timeout: None,
             pipeline: 1,
+            database: None,
+            client_name: None,
         }
     }
 }
@@ -333,9 +400,26 @@ impl Connection {
         self.tcp_nodelay
     }
 
+    /// The time in milliseconds allowed to establish a session, including
+    /// any TLS handshake.
+    pub fn timeout(&self) -> Option<usize> {
+        self.timeout
+    }
+
     pub fn pipeline(&self) -> usize {
         self.pipeline
     }
+
+    /// The database which is selected on each new session. Only used for
+    /// redis.
+    pub fn database(&self) -> Option<u64> {
+        self.database
+    }
+
+    /// The name which is set for each new session. Only used for redis.
+    pub fn client_name(&self) -> Option<String> {
+        self.client_name.clone()
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -375,6 +459,11 @@ pub struct Keyspace {
     key_type: FieldType,
     #[serde(default = "one")]
     batch_size: usize,
+    /// Applies the batch size to the fields of the hash commands for the
+    /// protocols which otherwise send a single field, eg: `hget` is sent as
+    /// `hmget` by the redis protocol.
+    #[serde(default)]
+    batch_fields: bool,
     pub(crate) key_distribution: Option<KeyDistribution>,
 }
 
@@ -414,11 +503,16 @@ impl Keyspace {
     pub fn batch_size(&self) -> usize {
         self.batch_size
     }
+
+    pub fn batch_fields(&self) -> bool {
+        self.batch_fields
+    }
 }
 
-#[derive(Deserialize, Clone, Copy, Eq, PartialEq)]
+#[derive(Deserialize, AsRefStr, Clone, Copy, Debug, Eq, PartialEq)]
 #[serde(rename_all = "snake_case")]
 #[serde(deny_unknown_fields)]
+#[strum(serialize_all = "snake_case")]
 pub enum Verb {
     /// Sends a simple 'ping' to a pingserver.
     Ping,
@@ -431,6 +525,8 @@ pub enum Verb {
     Set,
     /// Remove a key.
     Delete,
+    /// Increment the numeric value stored at a key.
+    Incr,
     /// Hash get, reads the value for one or more fields within the hash stored
     /// at the key.
     Hget,
@@ -457,12 +553,17 @@ pub enum Verb {
     Ltrim,
 }
 
-#[derive(Deserialize, Copy, Clone)]
+#[derive(Deserialize, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Command {
     verb: Verb,
     #[serde(default = "one")]
     weight: usize,
+    /// Protocol specific flags for the command. The memcache meta protocol
+    /// sends them with the command, eg: `"v t f"`, except for the quiet flag
+    /// `q` which is not allowed. The memcache binary protocol uses `k` and
+    /// `q` to select the opcode for a get.
+    flags: Option<String>,
 }
 
 impl Command {
@@ -473,6 +574,10 @@ impl Command {
     pub fn weight(&self) -> usize {
         self.weight
     }
+
+    pub fn flags(&self) -> Option<String> {
+        self.flags.clone()
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -538,13 +643,29 @@ impl Value {
 #[derive(Deserialize, Copy, Clone, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Request {
-    #[allow(dead_code)]
     timeout: Option<usize>,
+    timeout_penalty: Option<usize>,
     ratelimit: Option<usize>,
     ratelimit_model: Option<RatelimitModel>,
+    #[serde(default)]
+    open_loop: bool,
+    #[serde(default)]
+    verify: bool,
 }
 
 impl Request {
+    /// The time in milliseconds allowed for a response to be received.
+    pub fn timeout(&self) -> Option<usize> {
+        self.timeout
+    }
+
+    /// The latency in milliseconds which is recorded for each request that
+    /// times out. If not set, timed out requests are only counted and are not
+    /// included in the latency distribution.
+    pub fn timeout_penalty(&self) -> Option<usize> {
+        self.timeout_penalty
+    }
+
     pub fn ratelimit(&self) -> Option<usize> {
         self.ratelimit
     }
@@ -556,6 +677,23 @@ impl Request {
             Some(RatelimitModel::Normal) => rustcommon_ratelimiter::Refill::Normal,
         }
     }
+
+    /// In open-loop mode, the ratelimit determines when each request should
+    /// be sent, regardless of whether earlier requests have completed.
+    /// Requests which can not be sent on time are queued and their latency
+    /// includes the time spent waiting for a session.
+    pub fn open_loop(&self) -> bool {
+        self.open_loop
+    }
+
+    /// When verifying, each value which is written is derived from its key
+    /// and a version tracked by the client, and the values returned by gets
+    /// are checked against their checksum and the versions which may be
+    /// current. Keys which miss after a write without a TTL are counted as
+    /// lost, so keys which are evicted are counted too.
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -595,6 +733,26 @@ impl Tls {
     }
 }
 
+/// Credentials which are used to authenticate each new session. For redis
+/// these are sent with `AUTH` and for the memcache binary protocol they are
+/// sent using SASL `PLAIN`.
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Auth {
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl Auth {
+    pub fn username(&self) -> Option<String> {
+        self.username.clone()
+    }
+
+    pub fn password(&self) -> Option<String> {
+        self.password.clone()
+    }
+}
+
 struct ExitWatcher;
 impl Watcher for ExitWatcher {
     fn handle(&self, _event: WatchedEvent) {
@@ -609,9 +767,14 @@ pub struct Target {
     zk_path: Option<String>,
     zk_server: Option<String>,
     zk_endpoint_name: Option<String>,
+    routing: Option<Routing>,
 }
 
 impl Target {
+    pub fn routing(&self) -> Option<Routing> {
+        self.routing
+    }
+
     pub fn endpoints(&self) -> Vec<SocketAddr> {
         if self.zk_path.is_some() && self.zk_server.is_some() && self.zk_endpoint_name.is_some() {
             let zk_endpoint_name = self.zk_endpoint_name.as_deref().unwrap();
@@ -730,3 +893,34 @@ impl Waterfall {
         self.scale
     }
 }
+
+/// The record formats which can be read by `rpc-replay`.
+#[derive(Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[serde(deny_unknown_fields)]
+pub enum TraceFormat {
+    /// One comma separated line per request
+    #[default]
+    Ascii,
+    /// Fixed size 20 byte records
+    Binary,
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Trace {
+    /// A zstd compressed trace which every generated request is written to
+    output: Option<String>,
+    #[serde(default)]
+    format: TraceFormat,
+}
+
+impl Trace {
+    pub fn output(&self) -> Option<String> {
+        self.output.clone()
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+}
@@ -0,0 +1,241 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Generates the synthetic workload described by the keyspaces in the config,
+//! or takes the requests from a queue which is filled by another thread.
+
+use crate::config::{Keyspace, Stream};
+use crate::config_file::{Protocol, Verb};
+use crate::integrity::Versions;
+use crate::request::Request;
+use crate::*;
+use mpmc::Queue;
+use rand::rngs::SmallRng;
+use rand::Rng;
+use rand_distr::Alphanumeric;
+
+/// A request along with the keyspace it was generated from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Generated {
+    pub request: Request,
+    pub keyspace: Option<usize>,
+}
+
+pub struct Generator {
+    source: Source,
+    next: Option<Generated>,
+    /// the versions of the keys, which are only tracked when values are
+    /// verified
+    versions: Option<Arc<Versions>>,
+}
+
+enum Source {
+    /// requests are generated from the keyspaces in the config
+    Keyspaces { config: Arc<Config>, rng: SmallRng },
+    /// requests are taken from a queue which is shared between the workers,
+    /// such as the requests read from a trace by `rpc-replay`
+    Queue(Queue<Request>),
+}
+
+impl Generator {
+    /// Creates the generator for a worker. Each worker generates a different
+    /// sequence of requests, which is the same between runs with the same seed.
+    pub fn new(config: Arc<Config>, worker: usize) -> Self {
+        Self {
+            source: Source::Keyspaces {
+                rng: config.rng(worker, Stream::Requests),
+                config,
+            },
+            next: None,
+            versions: None,
+        }
+    }
+
+    /// Creates a generator which takes the requests from the queue.
+    pub fn from_queue(queue: Queue<Request>) -> Self {
+        Self {
+            source: Source::Queue(queue),
+            next: None,
+            versions: None,
+        }
+    }
+
+    /// Sets the versions which the values of generated writes are taken from.
+    pub fn set_versions(&mut self, versions: Option<Arc<Versions>>) {
+        self.versions = versions;
+    }
+
+    /// Returns true if the requests are taken from a queue, in which case
+    /// there may be no request available.
+    pub fn is_queue(&self) -> bool {
+        matches!(self.source, Source::Queue(_))
+    }
+
+    /// Returns the next request without consuming it. This allows the request
+    /// to be routed to an endpoint before a session is chosen.
+    pub fn peek(&mut self) -> Option<&Generated> {
+        if self.next.is_none() {
+            self.next = self.generate_inner();
+        }
+        self.next.as_ref()
+    }
+
+    /// Returns the next request, or `None` if the queue is empty.
+    pub fn generate(&mut self) -> Option<Generated> {
+        self.next.take().or_else(|| self.generate_inner())
+    }
+
+    fn generate_inner(&mut self) -> Option<Generated> {
+        match self.source {
+            Source::Keyspaces {
+                ref config,
+                ref mut rng,
+            } => Some(generate(config, rng, self.versions.as_deref())),
+            Source::Queue(ref queue) => queue.pop().map(|request| Generated {
+                request,
+                keyspace: None,
+            }),
+        }
+    }
+}
+
+fn generate(config: &Config, rng: &mut SmallRng, versions: Option<&Versions>) -> Generated {
+    // the ping and echo protocols have a single request type, so the
+    // configured commands are not used
+    let protocol = config.general().protocol();
+    if protocol == Protocol::Ping {
+        return Generated {
+            request: Request::Ping,
+            keyspace: None,
+        };
+    }
+
+    let keyspace = config.choose_keyspace(rng);
+    let request = if protocol == Protocol::Echo {
+        echo(rng, keyspace)
+    } else {
+        let verb = keyspace.choose_command(rng).verb();
+        // the thrift cache protocol has always sent a batch of fields, while
+        // the others send a single field unless the keyspace opts in
+        let fields = if protocol == Protocol::ThriftCache || keyspace.batch_fields() {
+            keyspace.batch_size()
+        } else {
+            1
+        };
+        request(rng, keyspace, verb, fields, versions)
+    };
+
+    Generated {
+        request,
+        keyspace: Some(keyspace.index()),
+    }
+}
+
+fn echo(rng: &mut SmallRng, keyspace: &Keyspace) -> Request {
+    let value = rng
+        .sample_iter(&Alphanumeric)
+        .take(keyspace.length())
+        .collect::<Vec<u8>>();
+    Request::Echo { value }
+}
+
+/// Generates a request for the verb. Commands which operate on multiple keys
+/// or values generate as many as the batch size of the keyspace, while the
+/// hash commands generate `fields` fields.
+fn request(
+    rng: &mut SmallRng,
+    keyspace: &Keyspace,
+    verb: Verb,
+    fields: usize,
+    versions: Option<&Versions>,
+) -> Request {
+    let batch = keyspace.batch_size();
+    match verb {
+        Verb::Ping => Request::Ping,
+        Verb::Echo => echo(rng, keyspace),
+        Verb::Get => Request::Get {
+            keys: (0..batch).map(|_| keyspace.generate_key(rng)).collect(),
+        },
+        Verb::Set => {
+            let key = keyspace.generate_key(rng);
+            Request::Set {
+                value: value(rng, keyspace, &key, versions),
+                key,
+                ttl: keyspace.ttl() as u32,
+            }
+        }
+        Verb::Delete => Request::Delete {
+            key: keyspace.generate_key(rng),
+        },
+        Verb::Incr => Request::Incr {
+            key: keyspace.generate_key(rng),
+        },
+        Verb::Hget => Request::Hget {
+            key: keyspace.generate_key(rng),
+            fields: inner_keys(rng, keyspace, fields),
+        },
+        Verb::Hset => Request::Hset {
+            key: keyspace.generate_key(rng),
+            fields: inner_keys(rng, keyspace, fields),
+            values: values(rng, keyspace, fields),
+            ttl: keyspace.ttl() as u32,
+        },
+        Verb::Hsetnx => Request::Hsetnx {
+            key: keyspace.generate_key(rng),
+            field: keyspace.generate_inner_key(rng).unwrap_or_default(),
+            value: keyspace.generate_value(rng).unwrap_or_default(),
+        },
+        Verb::Hdel => Request::Hdel {
+            key: keyspace.generate_key(rng),
+            fields: inner_keys(rng, keyspace, fields),
+        },
+        Verb::Rpush => Request::Rpush {
+            key: keyspace.generate_key(rng),
+            values: values(rng, keyspace, batch),
+        },
+        Verb::Rpushx => Request::Rpushx {
+            key: keyspace.generate_key(rng),
+            values: values(rng, keyspace, batch),
+        },
+        Verb::Count => Request::Count {
+            key: keyspace.generate_key(rng),
+        },
+        Verb::Lrange => Request::Lrange {
+            key: keyspace.generate_key(rng),
+        },
+        Verb::Ltrim => Request::Ltrim {
+            key: keyspace.generate_key(rng),
+        },
+    }
+}
+
+/// Returns the value for a write to the key. When values are verified, this
+/// is a new version of the value of the key, with the length of one of the
+/// configured values.
+fn value(
+    rng: &mut SmallRng,
+    keyspace: &Keyspace,
+    key: &[u8],
+    versions: Option<&Versions>,
+) -> Vec<u8> {
+    match versions {
+        Some(versions) => {
+            let len = keyspace.choose_value(rng).map(|v| v.length()).unwrap_or(0);
+            versions.value(key, len, keyspace.ttl() > 0)
+        }
+        None => keyspace.generate_value(rng).unwrap_or_default(),
+    }
+}
+
+fn inner_keys(rng: &mut SmallRng, keyspace: &Keyspace, count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|_| keyspace.generate_inner_key(rng).unwrap_or_default())
+        .collect()
+}
+
+fn values(rng: &mut SmallRng, keyspace: &Keyspace, count: usize) -> Vec<Vec<u8>> {
+    (0..count)
+        .map(|_| keyspace.generate_value(rng).unwrap_or_default())
+        .collect()
+}
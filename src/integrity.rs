@@ -0,0 +1,332 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Values which can be checked when they are read back from the server. Each
+//! value is derived from its key and a version, and is laid out as:
+//!
+//! `version (16 hex digits) | length (8 hex digits) | filler | crc (8 hex digits)`
+//!
+//! The filler is alphanumeric and seeded from the key and version, and the
+//! CRC covers the key along with the rest of the value, so a value which is
+//! returned for the wrong key does not match.
+//!
+//! Values are only created by `Versions::value`, which allocates the version
+//! when the request is generated. Versions are allocated from a counter which
+//! is shared by all the workers, and the versions of each key are tracked so
+//! that a value which is intact can still be found to be older or newer than
+//! it could be, or to be missing after it was written. As every key which is
+//! written is tracked, this is only suited to keyspaces which fit in memory.
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use rand::{Rng, SeedableRng};
+use rand_distr::Alphanumeric;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// the length of the version and length fields
+const HEADER: usize = 24;
+
+/// Values are never shorter than the header and the checksum.
+pub const MIN_VALUE_LENGTH: usize = HEADER + 8;
+
+/// The result of checking a value which was read back from the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// The value is intact and was written with this version.
+    Valid(u64),
+    /// The value is shorter than the length it was written with.
+    Truncated,
+    /// The value does not match its checksum or was written for another key.
+    Corrupt,
+}
+
+/// How an intact value compares with the versions written for its key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    /// The value may be the current value of the key.
+    Current,
+    /// A newer version had been acknowledged before the lookup was sent.
+    Stale,
+    /// The version was never written for the key by this client.
+    Phantom,
+}
+
+/// The newest versions of a key which have been written and acknowledged.
+#[derive(Clone, Copy, Default)]
+struct KeyVersions {
+    written: u64,
+    acknowledged: u64,
+    /// the newest version which was a delete
+    deleted: u64,
+    /// the newest version which was written with a TTL
+    expiring: u64,
+}
+
+/// The number of locks which the keys are divided between
+const SHARDS: usize = 64;
+
+/// Tracks the versions written for each key. A write is given a version when
+/// its value is generated, and a delete when it is sent. The version becomes
+/// the oldest one a lookup may return once the write is acknowledged.
+///
+/// Versions are allocated above the time the run started, in nanoseconds
+/// since the epoch, so the values left by an earlier run are not mistaken for
+/// values of this run. The keys are divided between several locks, so that
+/// the workers rarely wait on each other.
+pub struct Versions {
+    base: u64,
+    next: AtomicU64,
+    shards: Vec<Mutex<HashMap<Vec<u8>, KeyVersions>>>,
+}
+
+impl Default for Versions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Versions {
+    pub fn new() -> Self {
+        let base = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self::with_base(base)
+    }
+
+    fn with_base(base: u64) -> Self {
+        Self {
+            base,
+            next: AtomicU64::new(base),
+            shards: (0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, HashMap<Vec<u8>, KeyVersions>> {
+        self.shards[CRC.checksum(key) as usize % SHARDS]
+            .lock()
+            .unwrap()
+    }
+
+    /// Returns the version for a write to the key. `expiring` indicates that
+    /// the value is written with a TTL.
+    pub fn write(&self, key: &[u8], expiring: bool) -> u64 {
+        // the version is allocated while the key is locked, so the versions of
+        // each key are written in order
+        let mut shard = self.shard(key);
+        let version = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let versions = shard.entry(key.to_vec()).or_default();
+        versions.written = version;
+        if expiring {
+            versions.expiring = version;
+        }
+        version
+    }
+
+    /// Returns the version for a delete of the key.
+    pub fn delete(&self, key: &[u8]) -> u64 {
+        let mut shard = self.shard(key);
+        let version = self.next.fetch_add(1, Ordering::Relaxed) + 1;
+        let versions = shard.entry(key.to_vec()).or_default();
+        versions.written = version;
+        versions.deleted = version;
+        version
+    }
+
+    /// Returns a new version of the value of the key, which is `len` bytes
+    /// long unless that is shorter than `MIN_VALUE_LENGTH`. `expiring`
+    /// indicates that the value is written with a TTL.
+    pub fn value(&self, key: &[u8], len: usize, expiring: bool) -> Vec<u8> {
+        let version = self.write(key, expiring);
+        value(key, version, len)
+    }
+
+    /// Records that the write of the version to the key was acknowledged.
+    pub fn acknowledge(&self, key: &[u8], version: u64) {
+        if let Some(versions) = self.shard(key).get_mut(key) {
+            versions.acknowledged = versions.acknowledged.max(version);
+        }
+    }
+
+    /// Returns the oldest version of the key which a lookup that is being sent
+    /// may return.
+    pub fn oldest(&self, key: &[u8]) -> u64 {
+        self.shard(key)
+            .get(key)
+            .map(|v| v.acknowledged)
+            .unwrap_or(0)
+    }
+
+    /// Compares the version of a value returned by a lookup with the versions
+    /// of the key. `oldest` is the version returned by `oldest` when the
+    /// lookup was sent.
+    ///
+    /// As the checksum of a value covers its key, and values are only created
+    /// by `value`, an intact value with a version from this run was written
+    /// for this key. Values written by another client using the same keys at
+    /// the same time can not be told apart from those of this client.
+    pub fn freshness(&self, key: &[u8], version: u64, oldest: u64) -> Freshness {
+        if version <= self.base {
+            return Freshness::Phantom;
+        }
+        match self.shard(key).get(key) {
+            Some(versions) if version <= versions.written => {
+                if version < oldest {
+                    Freshness::Stale
+                } else {
+                    Freshness::Current
+                }
+            }
+            _ => Freshness::Phantom,
+        }
+    }
+
+    /// Returns whether a lookup which missed the key has lost a write. This
+    /// is the case when the write acknowledged before the lookup was sent
+    /// stored a value without a TTL, and the key has not been deleted since.
+    /// `oldest` is the version returned by `oldest` when the lookup was sent.
+    /// A key which was evicted can not be told apart from a lost write.
+    pub fn lost(&self, key: &[u8], oldest: u64) -> bool {
+        oldest > self.base
+            && self
+                .shard(key)
+                .get(key)
+                .map(|v| v.deleted < oldest && v.expiring != oldest)
+                .unwrap_or(false)
+    }
+}
+
+/// Returns the value for the key and version. The length is raised to
+/// `MIN_VALUE_LENGTH` if it is shorter.
+fn value(key: &[u8], version: u64, len: usize) -> Vec<u8> {
+    let len = len.max(MIN_VALUE_LENGTH);
+    let mut value = Vec::with_capacity(len);
+    value.extend_from_slice(format!("{:016x}{:08x}", version, len).as_bytes());
+
+    let seed = ((CRC.checksum(key) as u64) << 32) ^ version;
+    let rng = rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(seed);
+    value.extend(rng.sample_iter(&Alphanumeric).take(len - MIN_VALUE_LENGTH));
+
+    let crc = checksum(key, &value);
+    value.extend_from_slice(format!("{:08x}", crc).as_bytes());
+    value
+}
+
+/// Returns the version a value was written with, without checking the rest
+/// of the value.
+pub fn version(value: &[u8]) -> Option<u64> {
+    hex(value.get(0..16)?)
+}
+
+/// Checks a value which was returned for the key.
+pub fn check(key: &[u8], value: &[u8]) -> Check {
+    if value.len() < MIN_VALUE_LENGTH {
+        return Check::Truncated;
+    }
+    let (version, len) = match (hex(&value[0..16]), hex(&value[16..HEADER])) {
+        (Some(version), Some(len)) => (version, len as usize),
+        _ => return Check::Corrupt,
+    };
+    if value.len() < len {
+        return Check::Truncated;
+    }
+    if value.len() > len {
+        return Check::Corrupt;
+    }
+    let (body, crc) = value.split_at(len - 8);
+    if hex(crc) != Some(checksum(key, body) as u64) {
+        return Check::Corrupt;
+    }
+    Check::Valid(version)
+}
+
+fn checksum(key: &[u8], body: &[u8]) -> u32 {
+    let mut digest = CRC.digest();
+    digest.update(key);
+    digest.update(body);
+    digest.finalize()
+}
+
+fn hex(digits: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(digits).ok()?;
+    u64::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        let value = value(b"abc", 7, 64);
+        assert_eq!(value.len(), 64);
+        assert_eq!(check(b"abc", &value), Check::Valid(7));
+        assert_eq!(check(b"abd", &value), Check::Corrupt);
+        assert_eq!(check(b"abc", &value[0..40]), Check::Truncated);
+
+        let mut corrupt = value.clone();
+        corrupt[30] ^= 1;
+        assert_eq!(check(b"abc", &corrupt), Check::Corrupt);
+
+        assert_eq!(version(&value), Some(7));
+        assert_eq!(super::value(b"abc", 1, 0).len(), MIN_VALUE_LENGTH);
+    }
+
+    #[test]
+    fn versions() {
+        let versions = Versions::with_base(100);
+        let first = versions.write(b"abc", false);
+        versions.acknowledge(b"abc", first);
+        let second = versions.write(b"abc", false);
+        assert_eq!(versions.oldest(b"abc"), first);
+
+        // until the second write is acknowledged, either version may be read
+        assert_eq!(versions.freshness(b"abc", first, first), Freshness::Current);
+        assert_eq!(
+            versions.freshness(b"abc", second, first),
+            Freshness::Current
+        );
+
+        versions.acknowledge(b"abc", second);
+        let oldest = versions.oldest(b"abc");
+        assert_eq!(versions.freshness(b"abc", first, oldest), Freshness::Stale);
+        assert_eq!(
+            versions.freshness(b"abc", second + 1, oldest),
+            Freshness::Phantom
+        );
+        assert_eq!(versions.freshness(b"xyz", first, 0), Freshness::Phantom);
+
+        // values left by an earlier run
+        assert_eq!(versions.freshness(b"abc", 50, 0), Freshness::Phantom);
+    }
+
+    #[test]
+    fn lost() {
+        let versions = Versions::with_base(100);
+        assert!(!versions.lost(b"abc", versions.oldest(b"abc")));
+
+        let write = versions.write(b"abc", false);
+        versions.acknowledge(b"abc", write);
+        let oldest = versions.oldest(b"abc");
+        assert!(versions.lost(b"abc", oldest));
+
+        // a value which was written with a TTL may have expired
+        let expiring = versions.write(b"abc", true);
+        versions.acknowledge(b"abc", expiring);
+        assert!(!versions.lost(b"abc", versions.oldest(b"abc")));
+        assert!(versions.lost(b"abc", oldest));
+
+        // a delete which is sent after the lookup may remove the value
+        versions.delete(b"abc");
+        assert!(!versions.lost(b"abc", oldest));
+
+        let delete = versions.delete(b"xyz");
+        versions.acknowledge(b"xyz", delete);
+        assert!(!versions.lost(b"xyz", versions.oldest(b"xyz")));
+    }
+}
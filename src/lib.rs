@@ -9,40 +9,61 @@ extern crate rustcommon_logger;
 mod macros;
 
 mod admin;
+mod breakdown;
+mod cluster;
 // mod buffer;
 mod codec;
 mod config;
 mod config_file;
+mod generator;
+mod integrity;
 mod metrics;
+mod request;
+mod routing;
 mod session;
 mod time;
+mod timer;
+mod trace;
 mod worker;
 
 pub use crate::admin::Admin;
 pub use crate::config::Config;
+pub use crate::integrity::Versions;
 pub use crate::metrics::*;
-pub use crate::session::{Session, TcpStream};
+pub use crate::request::Request;
+pub use crate::session::{InFlight, Session, TcpStream};
 pub use crate::time::*;
 
 use rustcommon_heatmap::{AtomicHeatmap, AtomicU64};
 use rustcommon_logger::{File, LogBuilder, MultiLogBuilder, Output, Stdout};
 use rustcommon_ratelimiter::Ratelimiter;
 
+use breakdown::Breakdown;
+use mpmc::Queue;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread::JoinHandle;
+use trace::Trace;
 use worker::Worker;
 
 /// A structure which represents a runtime builder
 pub struct Builder {
+    config: Arc<Config>,
     admin: Admin,
     workers: Vec<Worker>,
+    breakdown: Arc<Breakdown>,
+    versions: Option<Arc<Versions>>,
 }
 
 impl Builder {
     /// Create a new runtime builder from the given config
     pub fn new(config: Option<&str>) -> Self {
-        let config = Config::new(config);
+        Self::from_config(Config::new(config))
+    }
 
+    /// Create a new runtime builder from a config which has already been
+    /// loaded
+    pub fn from_config(config: Config) -> Self {
         let config = Arc::new(config);
 
         let log_level = config.debug().log_level();
@@ -128,30 +149,95 @@ impl Builder {
                 None
             };
 
+        let breakdown = Arc::new(Breakdown::new(&config));
+
+        let versions = if config.request().verify() {
+            Some(Arc::new(Versions::new()))
+        } else {
+            None
+        };
+
+        let trace = Trace::new(&config).map(Arc::new);
+
         for endpoint in config.endpoints() {
             info!("endpoint: {}", endpoint);
         }
+        info!("seed: {}", config.seed());
 
         let mut workers = Vec::new();
-        for _ in 0..threads {
-            let mut worker = Worker::new(config.clone()).unwrap();
+        for id in 0..threads {
+            let mut worker = Worker::new(config.clone(), id as usize).unwrap();
             worker.set_connect_ratelimit(connect_ratelimit.clone());
             worker.set_reconnect_ratelimit(reconnect_ratelimit.clone());
             worker.set_request_ratelimit(request_ratelimit.clone());
             worker.set_connect_heatmap(connect_heatmap.clone());
             worker.set_request_heatmap(request_heatmap.clone());
             worker.set_request_waterfall(request_waterfall.clone());
+            worker.set_breakdown(Some(breakdown.clone()));
+            worker.set_versions(versions.clone());
+            worker.set_trace(trace.clone());
             workers.push(worker);
         }
 
-        let mut admin = Admin::new(config, log);
+        let mut admin = Admin::new(config.clone(), log);
         admin.set_connect_heatmap(connect_heatmap);
         admin.set_reconnect_ratelimit(reconnect_ratelimit);
         admin.set_request_heatmap(request_heatmap);
         admin.set_request_ratelimit(request_ratelimit);
         admin.set_request_waterfall(request_waterfall);
+        admin.set_breakdown(Some(breakdown.clone()));
+        admin.set_trace(trace);
+
+        Self {
+            config,
+            admin,
+            workers,
+            breakdown,
+            versions,
+        }
+    }
+
+    /// Returns the config the runtime is built from
+    pub fn config(&self) -> Arc<Config> {
+        self.config.clone()
+    }
+
+    /// Returns the versions of the keys if values are verified. Requests
+    /// which are generated outside of the workers must take the values they
+    /// write from these.
+    pub fn versions(&self) -> Option<Arc<Versions>> {
+        self.versions.clone()
+    }
 
-        Self { admin, workers }
+    /// Have the workers send the requests from the queue instead of
+    /// generating them
+    pub fn set_work_queue(&mut self, queue: Queue<Request>) {
+        for worker in self.workers.iter_mut() {
+            worker.set_work_queue(queue.clone());
+        }
+    }
+
+    /// Have each session send the requests from its own queue. Each queue is
+    /// for one of the configured endpoints, and the queues for each endpoint
+    /// are dealt out between the workers, so there should be one queue for
+    /// each session of each worker to each endpoint.
+    pub fn set_session_queues(&mut self, queues: Vec<(SocketAddr, Queue<Request>)>) {
+        let endpoints = self.config.endpoints();
+        let mut assigned: Vec<Vec<(SocketAddr, Queue<Request>)>> =
+            self.workers.iter().map(|_| Vec::new()).collect();
+        let mut dealt = vec![0; endpoints.len()];
+        for (endpoint, queue) in queues {
+            let index = match endpoints.iter().position(|e| *e == endpoint) {
+                Some(index) => index,
+                None => fatal!("session queue for unknown endpoint: {}", endpoint),
+            };
+            let worker = dealt[index] % assigned.len();
+            assigned[worker].push((endpoint, queue));
+            dealt[index] += 1;
+        }
+        for (worker, queues) in self.workers.iter_mut().zip(assigned) {
+            worker.set_session_queues(queues);
+        }
     }
 
     /// Launch the runtime
@@ -167,6 +253,8 @@ impl Builder {
         Runtime {
             admin_thread,
             worker_threads,
+            breakdown: self.breakdown,
+            request_timeout: self.config.request().timeout(),
         }
     }
 }
@@ -176,9 +264,62 @@ impl Builder {
 pub struct Runtime {
     admin_thread: JoinHandle<()>,
     worker_threads: Vec<JoinHandle<()>>,
+    breakdown: Arc<Breakdown>,
+    request_timeout: Option<usize>,
 }
 
 impl Runtime {
+    /// Waits until `requests` requests have been sent to the endpoints and
+    /// each of them has received a response or timed out. Requests can be
+    /// lost without either, such as when a session is closed, so this gives
+    /// up once there has been no progress for the request timeout, or for a
+    /// second if there is no timeout.
+    pub fn wait_for_requests(&self, requests: u64) {
+        let idle = Duration::from_millis(self.request_timeout.unwrap_or(1000) as u64);
+        let mut previous = None;
+        let mut progress = Instant::now();
+        loop {
+            let (mut sent, mut completed) = (0, 0);
+            for (_, stats) in self.breakdown.endpoints() {
+                let stats = stats.requests();
+                sent += stats.request();
+                completed += stats.response() + stats.timeout();
+            }
+            if sent >= requests && completed >= sent {
+                return;
+            }
+            let now = Instant::now();
+            if previous != Some((sent, completed)) {
+                previous = Some((sent, completed));
+                progress = now;
+            } else if now - progress >= idle {
+                return;
+            }
+            std::thread::sleep(core::time::Duration::from_millis(10));
+        }
+    }
+
+    /// Print the totals for each endpoint: the requests sent, the responses
+    /// received and the hit rate of the responses to lookups.
+    pub fn endpoint_summary(&self) {
+        for (endpoint, stats) in self.breakdown.endpoints() {
+            let stats = stats.requests();
+            let lookups = stats.hit() + stats.miss();
+            let hit_rate = if lookups > 0 {
+                100.0 * stats.hit() as f64 / lookups as f64
+            } else {
+                0.0
+            };
+            println!(
+                "Endpoint: {} Request: {} Response: {} Hit Rate: {:.2}%",
+                endpoint,
+                stats.request(),
+                stats.response(),
+                hit_rate
+            );
+        }
+    }
+
     /// Run the threads to completion
     pub fn wait(self) {
         let _ = self.admin_thread.join();
@@ -11,9 +11,18 @@ pub static CONNECT: Counter = Counter::new();
 #[metric(name = "connect_ex", description = "exceptions when calling connect")]
 pub static CONNECT_EX: Counter = Counter::new();
 
-#[metric(name = "connect_timeout", description = "connect timeouts")]
+#[metric(
+    name = "connect_timeout",
+    description = "connects, including session setup, which timed out"
+)]
 pub static CONNECT_TIMEOUT: Counter = Counter::new();
 
+#[metric(
+    name = "connect_setup_ex",
+    description = "sessions which failed connection setup"
+)]
+pub static CONNECT_SETUP_EX: Counter = Counter::new();
+
 #[metric(name = "request", description = "requests sent")]
 pub static REQUEST: Counter = Counter::new();
 
@@ -23,6 +32,30 @@ pub static REQUEST_EX: Counter = Counter::new();
 #[metric(name = "request_get", description = "get requests sent")]
 pub static REQUEST_GET: Counter = Counter::new();
 
+#[metric(
+    name = "request_timeout",
+    description = "requests which timed out waiting for a response"
+)]
+pub static REQUEST_TIMEOUT: Counter = Counter::new();
+
+#[metric(
+    name = "request_delayed",
+    description = "requests which were queued because no session was ready at their intended send time"
+)]
+pub static REQUEST_DELAYED: Counter = Counter::new();
+
+#[metric(
+    name = "request_dropped",
+    description = "requests which were not sent because too many were waiting for a session"
+)]
+pub static REQUEST_DROPPED: Counter = Counter::new();
+
+#[metric(
+    name = "request_unrouted",
+    description = "requests which were dropped because no session to their endpoint became ready"
+)]
+pub static REQUEST_UNROUTED: Counter = Counter::new();
+
 #[metric(name = "response", description = "responses received")]
 pub static RESPONSE: Counter = Counter::new();
 
@@ -38,12 +71,93 @@ pub static RESPONSE_EX: Counter = Counter::new();
 )]
 pub static RESPONSE_HIT: Counter = Counter::new();
 
+#[metric(
+    name = "response_miss",
+    description = "responses that indicated none of the keys were found"
+)]
+pub static RESPONSE_MISS: Counter = Counter::new();
+
+#[metric(
+    name = "response_stored",
+    description = "responses that indicated the item was stored"
+)]
+pub static RESPONSE_STORED: Counter = Counter::new();
+
+#[metric(
+    name = "response_not_stored",
+    description = "responses that indicated the item was not stored"
+)]
+pub static RESPONSE_NOT_STORED: Counter = Counter::new();
+
+#[metric(name = "response_byte", description = "bytes in decoded responses")]
+pub static RESPONSE_BYTE: Counter = Counter::new();
+
 /// distribution of response latencies
 // #[metric(name = "response_latency")]
 // pub static RESPONSE_LATENCY: Relaxed<Heatmap> = Relaxed::new(||
 //     Heatmap::new(1_000_000_000, 3, Duration::from_secs(60), Duration::from_secs(1))
 // );
 
+#[metric(name = "cluster_refresh", description = "cluster topology refreshes")]
+pub static CLUSTER_REFRESH: Counter = Counter::new();
+
+#[metric(
+    name = "cluster_moved",
+    description = "requests redirected to another cluster node by MOVED"
+)]
+pub static CLUSTER_MOVED: Counter = Counter::new();
+
+#[metric(
+    name = "cluster_ask",
+    description = "requests redirected to another cluster node by ASK"
+)]
+pub static CLUSTER_ASK: Counter = Counter::new();
+
+#[metric(
+    name = "replay_late",
+    description = "replayed requests which were queued later than the trace schedule"
+)]
+pub static REPLAY_LATE: Counter = Counter::new();
+
+#[metric(
+    name = "replay_behind",
+    description = "how far replay is behind the trace schedule in microseconds"
+)]
+pub static REPLAY_BEHIND: Gauge = Gauge::new();
+
+#[metric(name = "verify", description = "returned values which were verified")]
+pub static VERIFY: Counter = Counter::new();
+
+#[metric(
+    name = "verify_corrupt",
+    description = "returned values which did not match their checksum or key"
+)]
+pub static VERIFY_CORRUPT: Counter = Counter::new();
+
+#[metric(
+    name = "verify_truncated",
+    description = "returned values which were shorter than the length they were written with"
+)]
+pub static VERIFY_TRUNCATED: Counter = Counter::new();
+
+#[metric(
+    name = "verify_stale",
+    description = "returned values which were older than a write acknowledged before the get was sent"
+)]
+pub static VERIFY_STALE: Counter = Counter::new();
+
+#[metric(
+    name = "verify_phantom",
+    description = "returned values with a version which was never written for the key"
+)]
+pub static VERIFY_PHANTOM: Counter = Counter::new();
+
+#[metric(
+    name = "verify_lost",
+    description = "gets which missed a key after a write of its value without a ttl was acknowledged, including keys which were evicted"
+)]
+pub static VERIFY_LOST: Counter = Counter::new();
+
 #[metric(name = "close", description = "closed connections")]
 pub static CLOSE: Counter = Counter::new();
 
@@ -2,61 +2,29 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
-#![allow(clippy::unnecessary_unwrap)]
-
 #[macro_use]
 extern crate rustcommon_logger;
 
-use boring::ssl::*;
-use boring::x509::X509;
-use clap::{App, Arg};
-use mio::{Events, Poll, Token};
+use clap::{App, Arg, ArgMatches};
+use flate2::bufread::MultiGzDecoder;
 use mpmc::Queue;
 use rand::{Rng, RngCore, SeedableRng};
 use rand_distr::Alphanumeric;
-use rustcommon_logger::MultiLogBuilder;
-use rustcommon_logger::Stdout;
-use rustcommon_logger::{LevelFilter, LogBuilder};
 use rustcommon_ratelimiter::Ratelimiter;
-use slab::Slab;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
 use std::io::Read;
-use std::io::Write;
+use std::io::{BufRead, BufReader};
+use std::sync::Arc;
+use toml::value::{Table, Value};
 use zstd::Decoder;
 
-use std::collections::VecDeque;
-use std::fs::File;
-use std::io::{BufRead, BufReader, ErrorKind};
-use std::net::{SocketAddr, ToSocketAddrs};
-
 use rpc_perf::*;
 
-/// TODO(bmartin): this should be consolidated with rpc-perf
-use rustcommon_heatmap::AtomicHeatmap;
-use rustcommon_heatmap::AtomicU64;
-use std::sync::Arc;
-
-// include!("bindings.rs");
-
-// TODO(bmartin): this should be split up into a library and binary
 fn main() {
     let start = std::time::Instant::now();
 
-    // initialize logging
-    let log = LogBuilder::new()
-        .output(Box::new(Stdout::new()))
-        .log_queue_depth(1024)
-        .single_message_size(4096)
-        .build()
-        .expect("failed to initialize log");
-
-    let log = MultiLogBuilder::new()
-        .level_filter(LevelFilter::Info)
-        .default(log)
-        .build()
-        .start();
-
     // process command line arguments
-    // TODO(bmartin): consider moving to a file based config
     let matches = App::new("rpc-replay")
         .version("0.0.0")
         .author("Brian Martin <bmartin@twitter.com>")
@@ -65,24 +33,85 @@ fn main() {
             Arg::with_name("trace")
                 .long("trace")
                 .value_name("FILE")
-                .help("zstd compressed cache trace")
+                .help("cache trace, which may be zstd or gzip compressed")
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .value_name("FORMAT")
+                .help("format of the trace")
+                .possible_values(["csv", "binary", "oracle-general"])
+                .default_value("csv")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("binary-trace")
                 .long("binary-trace")
-                .help("indicates the trace is in the binary format")
+                .help("indicates the trace is in the binary format, same as '--format binary'")
                 .takes_value(false)
                 .required(false),
         )
+        .arg(
+            Arg::with_name("timestamp-unit")
+                .long("timestamp-unit")
+                .value_name("UNIT")
+                .help("unit of the timestamps in the trace")
+                .possible_values(["s", "ms", "us", "ns"])
+                .default_value("s")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("endpoint")
                 .long("endpoint")
                 .value_name("HOST:PORT")
-                .help("server endpoint to send traffic to")
+                .help("server endpoint to send traffic to, may be repeated")
                 .takes_value(true)
-                .required(true),
+                .multiple_occurrences(true)
+                .required_unless_present("zk-path"),
+        )
+        .arg(
+            Arg::with_name("zk-server")
+                .long("zk-server")
+                .value_name("HOST:PORT")
+                .help("zookeeper server to discover the endpoints from")
+                .takes_value(true)
+                .requires_all(&["zk-path", "zk-endpoint-name"]),
+        )
+        .arg(
+            Arg::with_name("zk-path")
+                .long("zk-path")
+                .value_name("PATH")
+                .help("zookeeper path of the serverset")
+                .takes_value(true)
+                .requires_all(&["zk-server", "zk-endpoint-name"]),
+        )
+        .arg(
+            Arg::with_name("zk-endpoint-name")
+                .long("zk-endpoint-name")
+                .value_name("NAME")
+                .help("name of the additional endpoint to use from the serverset")
+                .takes_value(true)
+                .requires_all(&["zk-server", "zk-path"]),
+        )
+        .arg(
+            Arg::with_name("routing")
+                .long("routing")
+                .value_name("HASH")
+                .help("hash which shards the keys across the endpoints")
+                .possible_values(["ketama", "jump"])
+                .default_value("ketama")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("protocol")
+                .long("protocol")
+                .value_name("PROTOCOL")
+                .help("protocol to send the requests with")
+                .possible_values(["memcache", "redis"])
+                .default_value("memcache")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("speed")
@@ -105,6 +134,76 @@ fn main() {
                 .help("number of connections to open from each worker")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("pipeline")
+                .long("pipeline")
+                .value_name("INT")
+                .help("number of requests to send on a connection at once")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("amplify")
+                .long("amplify")
+                .value_name("INT")
+                .help("replay this many disjoint copies of the keyspace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample")
+                .long("sample")
+                .value_name("FLOAT")
+                .help("fraction of the keys to replay, chosen by key hash")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .value_name("TIMESTAMP")
+                .help("skip the entries of the trace before this timestamp")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("end")
+                .long("end")
+                .value_name("TIMESTAMP")
+                .help("stop replaying at this timestamp in the trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("loop")
+                .long("loop")
+                .value_name("INT")
+                .help("number of times to replay the trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("value-size")
+                .long("value-size")
+                .value_name("BYTES")
+                .help("value length to use instead of the one in the trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ttl")
+                .long("ttl")
+                .value_name("SECONDS")
+                .help("ttl to use instead of the one in the trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .help("write values which can be verified and check the values returned by gets")
+                .takes_value(false)
+                .required(false),
+        )
+        .arg(
+            Arg::with_name("sharded")
+                .long("sharded")
+                .help("send the requests for each key over one connection, in trace order")
+                .takes_value(false)
+                .required(false),
+        )
         .arg(
             Arg::with_name("workers")
                 .long("workers")
@@ -141,84 +240,70 @@ fn main() {
 
     // config value parsing and defaults
     let trace = matches.value_of("trace").unwrap();
-    let endpoint = matches.value_of("endpoint").unwrap();
     let speed: Option<f64> = matches
         .value_of("speed")
         .map(|v| v.parse().expect("invalid value for 'speed'"));
     let rate: Option<usize> = matches
         .value_of("rate")
         .map(|v| v.parse().expect("invalid value for 'rate'"));
-    let poolsize: usize = matches
-        .value_of("poolsize")
-        .unwrap_or("1")
-        .parse()
-        .expect("invalid value for 'poolsize'");
     let workers: usize = matches
         .value_of("workers")
         .unwrap_or("1")
         .parse()
         .expect("invalid value for 'workers'");
-    let binary = matches.is_present("binary-trace");
-
-    // configure tls connector
-    let key = matches.value_of("tls-key");
-    let cert = matches.value_of("tls-cert");
-    let chain = matches.value_of("tls-chain");
-
-    let tls = if key.is_some() && cert.is_some() && chain.is_some() {
-        let mut builder = SslConnector::builder(SslMethod::tls_client())
-            .expect("failed to initialize TLS client");
-        builder.set_verify(SslVerifyMode::NONE);
-        builder
-            .set_certificate_file(cert.unwrap(), SslFiletype::PEM)
-            .expect("failed to set TLS cert");
-        let pem = std::fs::read(chain.unwrap()).expect("failed to read certificate chain");
-        let chain = X509::stack_from_pem(&pem).expect("bad certificate chain");
-        for cert in chain {
-            builder
-                .add_extra_chain_cert(cert)
-                .expect("bad certificate in chain");
-        }
-        builder
-            .set_private_key_file(key.unwrap(), SslFiletype::PEM)
-            .expect("failed to set TLS key");
-        let connector = builder.build();
-        Some(connector)
-    } else if key.is_none() && cert.is_none() && chain.is_none() {
-        None
+    let poolsize: usize = matches
+        .value_of("poolsize")
+        .unwrap_or("1")
+        .parse()
+        .expect("invalid value for 'poolsize'");
+    let format = if matches.is_present("binary-trace") {
+        "binary"
     } else {
-        fatal!("incomplete TLS config");
+        matches.value_of("format").unwrap()
+    };
+    // the length of each timestamp unit in nanoseconds
+    let unit = match matches.value_of("timestamp-unit") {
+        Some("ms") => 1_000_000,
+        Some("us") => 1_000,
+        Some("ns") => 1,
+        _ => 1_000_000_000,
+    };
+    let sharded = matches.is_present("sharded");
+
+    let mut builder = Builder::from_config(config(&matches));
+    let config = builder.config();
+    let versions = builder.versions();
+
+    // initialize work queues, grouped by endpoint, with one for each
+    // connection when sharded
+    let work: Vec<Vec<Queue<Request>>> = if sharded {
+        let endpoints = config.endpoints();
+        let queues = workers * poolsize * endpoints.len();
+        endpoints
+            .iter()
+            .map(|_| {
+                (0..workers * poolsize)
+                    .map(|_| Queue::with_capacity((1024 * 1024 / queues).max(1024)))
+                    .collect()
+            })
+            .collect()
+    } else {
+        vec![vec![Queue::with_capacity(1024 * 1024)]] // arbitrarily large
     };
 
-    // lookup socket address
-    let sockaddr = endpoint.to_socket_addrs().unwrap().next().unwrap();
-
-    // initialize work queue
-    let work = Queue::with_capacity(1024 * 1024); // arbitrarily large
-
-    let request_heatmap = Some(Arc::new(AtomicHeatmap::<u64, AtomicU64>::new(
-        1_000_000,
-        3,
-        Duration::from_secs(60),
-        Duration::from_millis(1000),
-    )));
-
-    // spawn admin
-    let mut admin = Admin::for_replay(None, log);
-    admin.set_request_heatmap(request_heatmap.clone());
-    let _admin_thread = std::thread::spawn(move || admin.run());
-
-    // spawn workers
-    for _ in 0..workers {
-        let mut worker = Worker::new(
-            sockaddr,
-            poolsize,
-            tls.clone(),
-            work.clone(),
-            request_heatmap.clone(),
-        );
-        std::thread::spawn(move || worker.run());
+    // spawn admin and workers
+    if sharded {
+        let queues = config
+            .endpoints()
+            .into_iter()
+            .zip(work.iter())
+            .flat_map(|(endpoint, queues)| queues.iter().map(move |q| (endpoint, q.clone())))
+            .collect();
+        builder.set_session_queues(queues);
+    } else {
+        builder.set_work_queue(work[0][0].clone());
     }
+    let runtime = builder.spawn();
 
     let controller: Box<dyn Controller> = if let Some(rate) = rate {
         Box::new(RateController::new(rate as u64, workers as u64))
@@ -227,24 +312,139 @@ fn main() {
         Box::new(SpeedController::new(speed))
     };
 
-    let mut generator = Generator::new(trace, work, binary, controller);
-    generator.run();
+    let mut generator = Generator::new(transform(&matches), config, work, unit, controller);
+    generator.set_versions(versions);
+    generator.run(|| reader(trace, format));
 
     println!("\n\n\n==========\nTotal Replay Time is: {} nanoseconds", start.elapsed().as_nanos());
+    generator.summary();
+    runtime.wait_for_requests(generator.sent());
+    runtime.endpoint_summary();
+}
+
+/// Builds the transformations of the trace from the command line.
+fn transform(matches: &ArgMatches) -> Transform {
+    fn parse<T: std::str::FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+        matches.value_of(name).map(|v| {
+            v.parse()
+                .unwrap_or_else(|_| panic!("invalid value for '{}'", name))
+        })
+    }
+
+    let transform = Transform {
+        amplify: parse(matches, "amplify").unwrap_or(1),
+        sample: parse(matches, "sample").unwrap_or(1.0),
+        start: parse(matches, "start"),
+        end: parse(matches, "end"),
+        loops: parse(matches, "loop").unwrap_or(1),
+        value_size: parse(matches, "value-size"),
+        ttl: parse(matches, "ttl"),
+    };
+    if transform.amplify == 0 {
+        fatal!("invalid configuration: 'amplify' must be at least 1");
+    }
+    if !(transform.sample > 0.0 && transform.sample <= 1.0) {
+        fatal!("invalid configuration: 'sample' must be in the range (0, 1]");
+    }
+    transform
 }
 
+/// Builds the config for the workers and admin from the command line.
+fn config(matches: &ArgMatches) -> Config {
+    let count = |name: &str| -> i64 {
+        matches
+            .value_of(name)
+            .unwrap_or("1")
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid value for '{}'", name))
+    };
+
+    // redis uses RESP so that keys and values may contain any bytes
+    let protocol = match matches.value_of("protocol") {
+        Some("redis") => "redis_resp",
+        _ => "memcache",
+    };
+
+    let mut general = Table::new();
+    general.insert("protocol".into(), protocol.into());
+    general.insert("threads".into(), count("workers").into());
+    // replay runs until the end of the trace
+    general.insert("service".into(), true.into());
+
+    let mut target = Table::new();
+    let endpoints: Vec<&str> = matches
+        .values_of("endpoint")
+        .map(|v| v.collect())
+        .unwrap_or_default();
+    target.insert("endpoints".into(), endpoints.into());
+    for arg in ["zk-server", "zk-path", "zk-endpoint-name"] {
+        if let Some(value) = matches.value_of(arg) {
+            target.insert(arg.replace('-', "_"), value.into());
+        }
+    }
+    let routing = matches.value_of("routing").unwrap();
+    target.insert("routing".into(), routing.into());
+
+    let mut connection = Table::new();
+    connection.insert("poolsize".into(), count("poolsize").into());
+    connection.insert("pipeline".into(), count("pipeline").into());
+
+    let mut request = Table::new();
+    request.insert("verify".into(), matches.is_present("verify").into());
+
+    let mut config = Table::new();
+    config.insert("general".into(), Value::Table(general));
+    config.insert("target".into(), Value::Table(target));
+    config.insert("connection".into(), Value::Table(connection));
+    config.insert("request".into(), Value::Table(request));
+
+    let mut tls = Table::new();
+    for (arg, field) in [
+        ("tls-chain", "certificate_chain"),
+        ("tls-key", "private_key"),
+        ("tls-cert", "certificate"),
+    ] {
+        if let Some(file) = matches.value_of(arg) {
+            tls.insert(field.into(), file.into());
+        }
+    }
+    if !tls.is_empty() {
+        tls.insert("verify".into(), false.into());
+        config.insert("tls".into(), Value::Table(tls));
+    }
+
+    Config::from_toml(Value::Table(config))
+}
+
+/// Requests behind their scheduled time by more than this are counted as late
+const LATE_THRESHOLD: Duration = Duration::from_millis(1);
+
 pub trait Controller {
+    /// Waits until the request with the timestamp, in nanoseconds, should be
+    /// sent.
     fn delay(&mut self, ts: u64);
 }
 
+/// The number of entries of each operation which were sent and skipped.
+#[derive(Default)]
 pub struct GeneratorStats {
-    sent: usize,
-    skip: usize,
+    ops: BTreeMap<String, (usize, usize)>,
 }
 
-impl Default for GeneratorStats {
-    fn default() -> Self {
-        Self { sent: 0, skip: 0 }
+impl GeneratorStats {
+    fn sent(&mut self, op: &str) {
+        self.entry(op).0 += 1;
+    }
+
+    fn skip(&mut self, op: &str) {
+        self.entry(op).1 += 1;
+    }
+
+    fn entry(&mut self, op: &str) -> &mut (usize, usize) {
+        if !self.ops.contains_key(op) {
+            self.ops.insert(op.to_string(), (0, 0));
+        }
+        self.ops.get_mut(op).unwrap()
     }
 }
 
@@ -272,9 +472,12 @@ impl Controller for RateController {
     }
 }
 
+/// Replays the trace at a multiple of the speed it was recorded at. Each
+/// request is scheduled at its offset from the first request in the trace,
+/// divided by the speed.
 pub struct SpeedController {
-    ts_sec: u64,
-    next: Instant,
+    /// the timestamp of the first request and the time it was replayed
+    start: Option<(u64, Instant)>,
     speed: f64,
 }
 
@@ -286,415 +489,631 @@ impl Default for SpeedController {
 
 impl SpeedController {
     pub fn new(speed: f64) -> Self {
-        Self {
-            ts_sec: 0,
-            next: Instant::now(),
-            speed,
-        }
+        Self { start: None, speed }
     }
 }
 
 impl Controller for SpeedController {
     fn delay(&mut self, ts: u64) {
-        // handle new timestamp in log
-        if ts > self.ts_sec {
-            let mut now = Instant::now();
-            // info!("ts: {} sent: {} skip: {}", ts, sent, skip);
-            if self.ts_sec != 0 {
-                let log_dur = Duration::from_nanos(
-                    (((ts - self.ts_sec) * 1_000_000_000) as f64 / self.speed) as u64,
-                );
-                self.next += log_dur;
-                if now > self.next {
-                    warn!("falling behind... try reducing replay rate");
-                }
-            }
-            self.ts_sec = ts;
-            // delay if needed
-            while now < self.next {
-                std::thread::sleep(core::time::Duration::from_micros(100));
-                now = Instant::now();
+        let mut now = Instant::now();
+        let (first, start) = *self.start.get_or_insert((ts, now));
+        let offset = (ts.saturating_sub(first) as f64 / self.speed) as u64;
+        let scheduled = start + Duration::from_nanos(offset);
+
+        if now > scheduled {
+            let behind = now - scheduled;
+            REPLAY_BEHIND.set((behind.as_nanos() / 1_000) as i64);
+            if behind > LATE_THRESHOLD {
+                REPLAY_LATE.increment();
             }
+            return;
+        }
+
+        REPLAY_BEHIND.set(0);
+        while now < scheduled {
+            let remaining = (scheduled - now).as_nanos().min(100_000);
+            std::thread::sleep(core::time::Duration::from_nanos(remaining));
+            now = Instant::now();
+        }
+    }
+}
+
+/// Changes made to the trace as it is replayed.
+pub struct Transform {
+    /// the number of disjoint copies of the keyspace which are replayed
+    amplify: usize,
+    /// the fraction of the keys which are replayed
+    sample: f64,
+    /// the first timestamp of the trace which is replayed
+    start: Option<u64>,
+    /// the timestamp of the trace at which the replay stops
+    end: Option<u64>,
+    /// the number of times the trace is replayed
+    loops: usize,
+    value_size: Option<usize>,
+    ttl: Option<u32>,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            amplify: 1,
+            sample: 1.0,
+            start: None,
+            end: None,
+            loops: 1,
+            value_size: None,
+            ttl: None,
+        }
+    }
+}
+
+impl Transform {
+    /// Returns whether the key is in the sample. Keys are sampled by their
+    /// hash, so the same keys are replayed each time, including by other
+    /// builds of `rpc-replay`.
+    fn sampled(&self, key: &str) -> bool {
+        if self.sample >= 1.0 {
+            return true;
+        }
+        // the high bits of the hash are poorly mixed for short keys, so the
+        // key is sampled by the low digits
+        fnv1a_64(key.as_bytes()) % 1_000_000 < (self.sample * 1_000_000.0) as u64
+    }
+
+    /// Returns whether the timestamp is before the start of the window.
+    fn before(&self, ts: u64) -> bool {
+        matches!(self.start, Some(start) if ts < start)
+    }
+
+    /// Returns whether the timestamp is at or after the end of the window.
+    fn after(&self, ts: u64) -> bool {
+        matches!(self.end, Some(end) if ts >= end)
+    }
+
+    /// Returns the copies of the record which are replayed. When the keyspace
+    /// is amplified, each copy has the index of the copy appended to its key.
+    fn apply(&self, record: Record) -> Vec<Record> {
+        if self.amplify == 1 {
+            let key = record.key().to_string();
+            return vec![record.rewrite(key, self.value_size, self.ttl)];
         }
+        (0..self.amplify)
+            .map(|copy| {
+                let key = format!("{}-{}", record.key(), copy);
+                record.rewrite(key, self.value_size, self.ttl)
+            })
+            .collect()
     }
 }
 
 pub struct Generator {
     stats: GeneratorStats,
     controller: Box<dyn Controller>,
-    trace: String,
-    work: Queue<Request>,
-    binary: bool,
+    transform: Transform,
+    config: Arc<Config>,
+    /// the work queues for each endpoint, or a single group of queues which
+    /// are shared by all the endpoints
+    work: Vec<Vec<Queue<Request>>>,
+    /// the length of each timestamp unit in nanoseconds
+    unit: u64,
+    rng: rand_xoshiro::Xoshiro256PlusPlus,
+    /// the versions of the keys, which are only tracked when values are
+    /// verified
+    versions: Option<Arc<Versions>>,
 }
 
 impl Generator {
     pub fn new(
-        trace: &str,
-        work: Queue<Request>,
-        binary: bool,
+        transform: Transform,
+        config: Arc<Config>,
+        work: Vec<Vec<Queue<Request>>>,
+        unit: u64,
         controller: Box<dyn Controller>,
     ) -> Self {
         Self {
             stats: GeneratorStats::default(),
             controller,
-            trace: trace.to_string(),
+            transform,
+            config,
             work,
-            binary,
+            unit,
+            rng: rng(),
+            versions: None,
         }
     }
 
-    pub fn run(&mut self) {
-        if self.binary {
-            self.binary()
-        } else {
-            self.ascii()
-        }
+    /// Sets the versions which the values of writes are taken from.
+    pub fn set_versions(&mut self, versions: Option<Arc<Versions>>) {
+        self.versions = versions;
     }
 
-    fn ascii(&mut self) {
-        // open files
-        let zlog = File::open(&self.trace).expect("failed to open input zlog");
-        let zbuf = BufReader::new(zlog);
-        let log = Decoder::with_buffer(zbuf).expect("failed to init zstd decoder");
-        let buf_log = BufReader::new(log);
-        let mut lines = buf_log.lines();
-
-        while let Some(Ok(line)) = lines.next() {
-            let parts: Vec<&str> = line.split(',').collect();
-
-            let ts: u64 = parts[0].parse::<u64>().expect("invalid timestamp") + 1;
-            let verb = parts[5];
-
-            let key = parts[1].to_string();
-            let vlen: usize = parts[3].parse().expect("failed to parse vlen");
-            let ttl: u32 = parts[6].parse().expect("failed to parse ttl");
-
-            let mut request = match verb {
-                "get" => Request::Get { key },
-                "gets" => Request::Gets { key },
-                "set" => Request::Set { key, vlen, ttl },
-                "add" => Request::Add { key, vlen, ttl },
-                "replace" => Request::Replace { key, vlen, ttl },
-                "delete" => Request::Delete { key },
-                _ => {
-                    self.stats.skip += 1;
-                    continue;
+    /// Replays the trace from the readers which are opened for each loop.
+    /// Timestamps are taken relative to the first entry replayed, and each
+    /// loop is scheduled to follow the one before it. The trace is expected to
+    /// be in timestamp order, so the replay of a loop stops at the end of the
+    /// window.
+    pub fn run<F: Fn() -> Box<dyn TraceReader>>(&mut self, open: F) {
+        let mut offset = 0;
+        for _ in 0..self.transform.loops {
+            let mut reader = open();
+            let mut first = None;
+            let mut last = 0;
+            while let Some(entry) = reader.read() {
+                match entry {
+                    Ok((ts, record)) => {
+                        if self.transform.before(ts) {
+                            continue;
+                        }
+                        if self.transform.after(ts) {
+                            break;
+                        }
+                        if !self.transform.sampled(record.key()) {
+                            continue;
+                        }
+                        let first = *first.get_or_insert(ts);
+                        last = last.max(ts);
+                        let ts = offset + ts.saturating_sub(first);
+                        // each copy is delayed, so that a rate applies to the
+                        // requests which are sent rather than the records
+                        for record in self.transform.apply(record) {
+                            self.controller.delay(ts * self.unit);
+                            self.stats.sent(record.op());
+                            self.push(record);
+                        }
+                    }
+                    Err(op) => {
+                        self.stats.skip(&op);
+                    }
                 }
-            };
-
-            self.controller.delay(ts);
-
-            while let Err(r) = self.work.push(request) {
-                request = r;
             }
-
-            self.stats.sent += 1;
+            if let Some(first) = first {
+                offset += last - first + 1;
+            }
         }
     }
 
-    fn binary(&mut self) {
-        // open files
-        let zlog = File::open(&self.trace).expect("failed to open input zlog");
-        let zbuf = BufReader::new(zlog);
-        let mut log = Decoder::with_buffer(zbuf).expect("failed to init zstd decoder");
+    /// Returns the number of requests which have been sent.
+    pub fn sent(&self) -> u64 {
+        self.stats.ops.values().map(|(sent, _)| *sent as u64).sum()
+    }
 
-        let mut tmp = [0_u8; 20];
-        while log.read_exact(&mut tmp).is_ok() {
-            let ts: u64 = u32::from_le_bytes([tmp[0], tmp[1], tmp[2], tmp[3]]) as u64;
-            let keyid: u64 = u64::from_le_bytes([
-                tmp[4], tmp[5], tmp[6], tmp[7], tmp[8], tmp[9], tmp[10], tmp[11],
-            ]);
-            let klen_vlen: u32 = u32::from_le_bytes([tmp[12], tmp[13], tmp[14], tmp[15]]);
-            let op_ttl: u32 = u32::from_le_bytes([tmp[16], tmp[17], tmp[18], tmp[19]]);
-            let op: u8 = (op_ttl >> 24) as u8;
-            let ttl: u32 = op_ttl & 0x00FF_FFFF;
-            let klen = klen_vlen >> 22;
-            let vlen: usize = (klen_vlen & 0x003F_FFFF) as usize;
-
-            let key = format!("{:01$}", keyid, klen as usize);
-
-            let mut request = match op {
-                1 => Request::Get { key },
-                2 => Request::Gets { key },
-                3 => Request::Set { key, vlen, ttl },
-                4 => Request::Add { key, vlen, ttl },
-                6 => Request::Replace { key, vlen, ttl },
-                9 => Request::Delete { key },
-                _ => {
-                    self.stats.skip += 1;
-                    continue;
-                }
-            };
+    /// Prints the number of entries of each operation which were sent and
+    /// skipped.
+    pub fn summary(&self) {
+        println!("Operation: Sent Skipped");
+        let mut total = (0, 0);
+        for (op, (sent, skip)) in &self.stats.ops {
+            println!("{}: {} {}", op, sent, skip);
+            total.0 += sent;
+            total.1 += skip;
+        }
+        println!("total: {} {}", total.0, total.1);
+    }
 
-            self.controller.delay(ts);
+    /// Returns a value of the length recorded in the trace for a write to the
+    /// key. When values are verified, this is a new version of the value of
+    /// the key, and otherwise it is random.
+    fn value(&mut self, key: &[u8], vlen: usize, ttl: u32) -> Vec<u8> {
+        if let Some(ref versions) = self.versions {
+            return versions.value(key, vlen, ttl > 0);
+        }
+        (&mut self.rng as &mut dyn RngCore)
+            .sample_iter(&Alphanumeric)
+            .take(vlen)
+            .collect::<Vec<u8>>()
+    }
 
-            while let Err(r) = self.work.push(request) {
-                request = r;
-            }
+    /// Pushes the request for the record onto a work queue. Requests for the
+    /// same key are always pushed to the same queue, so that they are sent in
+    /// the order of the trace when each connection has its own queue. The
+    /// queues are grouped by endpoint, and the group is chosen by routing the
+    /// key to an endpoint.
+    fn push(&mut self, record: Record) {
+        let mut request = self.request(record);
+        let key = request.key().unwrap_or_default();
+        let hash = fnv1a_64(key);
+        let endpoint = if self.work.len() > 1 {
+            self.config
+                .route(key)
+                .unwrap_or((hash % self.work.len() as u64) as usize)
+        } else {
+            0
+        };
+        let group = &self.work[endpoint];
+        let queue = &group[(hash % group.len() as u64) as usize];
+        while let Err(r) = queue.push(request) {
+            request = r;
+        }
+    }
 
-            self.stats.sent += 1;
+    /// Converts the record into a request with a value of the recorded length.
+    fn request(&mut self, record: Record) -> Request {
+        match record {
+            Record::Get { key } => Request::Get {
+                keys: vec![key.into_bytes()],
+            },
+            Record::Gets { key } => Request::Gets {
+                keys: vec![key.into_bytes()],
+            },
+            Record::Set { key, vlen, ttl } => {
+                let key = key.into_bytes();
+                let value = self.value(&key, vlen, ttl);
+                Request::Set { key, value, ttl }
+            }
+            Record::Add { key, vlen, ttl } => {
+                let key = key.into_bytes();
+                let value = self.value(&key, vlen, ttl);
+                Request::Add { key, value, ttl }
+            }
+            Record::Replace { key, vlen, ttl } => {
+                let key = key.into_bytes();
+                let value = self.value(&key, vlen, ttl);
+                Request::Replace { key, value, ttl }
+            }
+            Record::Delete { key } => Request::Delete {
+                key: key.into_bytes(),
+            },
         }
     }
 }
 
+/// The 64-bit FNV-1a hash, which unlike the `DefaultHasher` is stable across
+/// releases.
+fn fnv1a_64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
 // A very fast PRNG
 pub fn rng() -> rand_xoshiro::Xoshiro256PlusPlus {
     rand_xoshiro::Xoshiro256PlusPlus::seed_from_u64(0)
 }
 
-struct Worker {
-    sessions: Slab<Session>,
-    ready_queue: VecDeque<Token>,
-    poll: Poll,
-    work: Queue<Request>,
-    request_heatmap: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
-    rng: rand_xoshiro::Xoshiro256PlusPlus,
+/// A request read from the trace. Values are not recorded, so only their
+/// length is known until the request is queued.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Record {
+    Get { key: String },
+    Gets { key: String },
+    Set { key: String, vlen: usize, ttl: u32 },
+    Add { key: String, vlen: usize, ttl: u32 },
+    Replace { key: String, vlen: usize, ttl: u32 },
+    Delete { key: String },
 }
 
-impl Worker {
-    pub fn new(
-        addr: SocketAddr,
-        poolsize: usize,
-        tls: Option<SslConnector>,
-        work: Queue<Request>,
-        request_heatmap: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
-    ) -> Self {
-        let poll = mio::Poll::new().unwrap();
-
-        let mut sessions: Slab<Session> = Slab::with_capacity(poolsize);
-
-        let mut ready_queue: VecDeque<Token> = VecDeque::with_capacity(poolsize);
-
-        for _ in 0..poolsize {
-            let stream = TcpStream::connect(addr).expect("failed to connect");
-            let mut session = if let Some(tls) = tls.as_ref() {
-                match tls.connect("localhost", stream) {
-                    Ok(stream) => Session::tls_with_capacity(stream, 1024, 512 * 1024),
-                    Err(HandshakeError::WouldBlock(stream)) => {
-                        Session::handshaking_with_capacity(stream, 1024, 512 * 1024)
-                    }
-                    Err(_) => {
-                        panic!("tls failure");
-                    }
-                }
-            } else {
-                Session::plain_with_capacity(stream, 1024, 512 * 1024)
-            };
-            let entry = sessions.vacant_entry();
-            let token = Token(entry.key());
-            ready_queue.push_back(token);
-            session.set_token(token);
-            session.set_timestamp(Instant::now());
-            session.register(&poll).expect("register failed");
-            entry.insert(session);
+impl Record {
+    /// The name of the operation in the trace.
+    pub fn op(&self) -> &'static str {
+        match self {
+            Self::Get { .. } => "get",
+            Self::Gets { .. } => "gets",
+            Self::Set { .. } => "set",
+            Self::Add { .. } => "add",
+            Self::Replace { .. } => "replace",
+            Self::Delete { .. } => "delete",
         }
+    }
 
-        Self {
-            sessions,
-            ready_queue,
-            poll,
-            work,
-            request_heatmap,
-            rng: rng(),
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Get { key }
+            | Self::Gets { key }
+            | Self::Set { key, .. }
+            | Self::Add { key, .. }
+            | Self::Replace { key, .. }
+            | Self::Delete { key } => key,
         }
     }
 
-    pub fn send_request(&mut self, token: Token, request: Request) {
-        let session = self.sessions.get_mut(token.0).expect("bad token");
-        REQUEST.increment();
-        match request {
-            Request::Get { key } => {
-                REQUEST_GET.increment();
-                let _ = session.write_all(format!("get {}\r\n", key).as_bytes());
-                debug!("get {}", key);
-            }
-            Request::Gets { key } => {
-                REQUEST_GET.increment();
-                let _ = session.write_all(format!("gets {}\r\n", key).as_bytes());
-                debug!("get {}", key);
-            }
-            Request::Set { key, vlen, ttl } => {
-                let value = (&mut self.rng as &mut dyn RngCore)
-                    .sample_iter(&Alphanumeric)
-                    .take(vlen)
-                    .collect::<Vec<u8>>();
-                let _ = session.write_all(format!("set {} 0 {} {}\r\n", key, ttl, vlen).as_bytes());
-                let _ = session.write_all(&value);
-                let _ = session.write_all(b"\r\n");
-                debug!("set {} 0 {} {}", key, ttl, vlen);
-            }
-            Request::Add { key, vlen, ttl } => {
-                let value = (&mut self.rng as &mut dyn RngCore)
-                    .sample_iter(&Alphanumeric)
-                    .take(vlen)
-                    .collect::<Vec<u8>>();
-                let _ = session.write_all(format!("add {} 0 {} {}\r\n", key, ttl, vlen).as_bytes());
-                let _ = session.write_all(&value);
-                let _ = session.write_all(b"\r\n");
-                debug!("add {} 0 {} {}", key, ttl, vlen);
-            }
-            Request::Replace { key, vlen, ttl } => {
-                let value = (&mut self.rng as &mut dyn RngCore)
-                    .sample_iter(&Alphanumeric)
-                    .take(vlen)
-                    .collect::<Vec<u8>>();
-                let _ =
-                    session.write_all(format!("replace {} 0 {} {}\r\n", key, ttl, vlen).as_bytes());
-                let _ = session.write_all(&value);
-                let _ = session.write_all(b"\r\n");
-                debug!("replace {} 0 {} {}", key, ttl, vlen);
-            }
-            Request::Delete { key } => {
-                let _ = session.write_all(format!("delete {}\r\n", key).as_bytes());
-                debug!("delete {}", key);
-            }
+    /// Returns a copy of the record for another key, with the value length
+    /// and ttl replaced where they are given.
+    fn rewrite(&self, key: String, vlen: Option<usize>, ttl: Option<u32>) -> Self {
+        match *self {
+            Self::Get { .. } => Self::Get { key },
+            Self::Gets { .. } => Self::Gets { key },
+            Self::Set {
+                vlen: v, ttl: t, ..
+            } => Self::Set {
+                key,
+                vlen: vlen.unwrap_or(v),
+                ttl: ttl.unwrap_or(t),
+            },
+            Self::Add {
+                vlen: v, ttl: t, ..
+            } => Self::Add {
+                key,
+                vlen: vlen.unwrap_or(v),
+                ttl: ttl.unwrap_or(t),
+            },
+            Self::Replace {
+                vlen: v, ttl: t, ..
+            } => Self::Replace {
+                key,
+                vlen: vlen.unwrap_or(v),
+                ttl: ttl.unwrap_or(t),
+            },
+            Self::Delete { .. } => Self::Delete { key },
         }
-        session.set_timestamp(Instant::now());
-        let _ = session.flush();
-        let _ = session.reregister(&self.poll);
-    }
-
-    pub fn run(&mut self) {
-        let mut events = Events::with_capacity(1024);
-        loop {
-            if let Some(token) = self.ready_queue.pop_front() {
-                if let Some(request) = self.work.pop() {
-                    self.send_request(token, request);
-                } else {
-                    self.ready_queue.push_front(token);
-                }
-            }
+    }
+}
 
-            let _ = self
-                .poll
-                .poll(&mut events, Some(std::time::Duration::from_millis(1)));
+/// An entry of the trace, which is either a record which can be replayed
+/// along with its timestamp, in the units of the trace, or the name of an
+/// operation which can not be. Entries which can not be parsed are named
+/// `invalid`.
+pub type Entry = Result<(u64, Record), String>;
 
-            for event in &events {
-                let token = event.token();
-                let session = self.sessions.get_mut(token.0).expect("unknown token");
+/// Reads the entries of a trace in one of the supported formats.
+pub trait TraceReader {
+    /// Returns the next entry, or `None` at the end of the trace.
+    fn read(&mut self) -> Option<Entry>;
+}
 
-                // handle error events first
-                if event.is_error() {
-                    let _ = session.deregister(&self.poll);
-                    session.close();
-                    warn!("error");
-                }
+/// Opens the trace, which is decompressed if it starts with the magic bytes of
+/// a zstd or gzip stream, and returns a reader for its format.
+fn reader(path: &str, format: &str) -> Box<dyn TraceReader> {
+    let file = File::open(path).unwrap_or_else(|e| fatal!("failed to open trace: {}: {}", path, e));
+    let input = decompress(BufReader::new(file)).expect("failed to read trace");
+    match format {
+        "binary" => Box::new(BinaryReader::new(input)),
+        "oracle-general" => Box::new(OracleGeneralReader::new(input)),
+        _ => Box::new(CsvReader::new(BufReader::new(input))),
+    }
+}
 
-                // handle handshaking
-                if session.is_handshaking() {
-                    if let Err(e) = session.do_handshake() {
-                        if e.kind() != ErrorKind::WouldBlock {
-                            let _ = session.deregister(&self.poll);
-                            session.close();
-                            warn!("error");
-                        }
-                    }
-                    if session.is_handshaking() {
-                        let _ = session.reregister(&self.poll);
-                        continue;
-                    }
-                }
+fn decompress<'a, R: BufRead + 'a>(mut input: R) -> std::io::Result<Box<dyn Read + 'a>> {
+    let magic = input.fill_buf()?;
+    if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+        Ok(Box::new(Decoder::with_buffer(input)?))
+    } else if magic.starts_with(&[0x1F, 0x8B]) {
+        Ok(Box::new(MultiGzDecoder::new(input)))
+    } else {
+        Ok(Box::new(input))
+    }
+}
 
-                // handle reads
-                if event.is_readable() {
-                    match session.fill_buf().map(|b| b.len()) {
-                        Ok(0) => {
-                            let _ = session.deregister(&self.poll);
-                            session.close();
-                            warn!("server hangup");
-                        }
-                        Ok(_) => match decode(session) {
-                            Ok(_) => {
-                                RESPONSE.increment();
-                                if let Some(ref heatmap) = self.request_heatmap {
-                                    let now = Instant::now();
-                                    let elapsed = now - session.timestamp();
-                                    let us = (elapsed.as_secs_f64() * 1_000_000.0) as u64;
-                                    heatmap.increment(now, us, 1);
-                                }
-                                if let Some(request) = self.work.pop() {
-                                    self.send_request(token, request);
-                                } else {
-                                    self.ready_queue.push_back(token);
-                                }
-
-                                continue;
-                            }
-                            Err(ParseError::Incomplete) => {
-                                continue;
-                            }
-                            Err(_) => {
-                                let _ = session.deregister(&self.poll);
-                                session.close();
-                                warn!("parse error");
-                            }
-                        },
-                        Err(e) => {
-                            let _ = session.deregister(&self.poll);
-                            session.close();
-                            warn!("read error: {}", e);
-                        }
-                    }
-                }
+/// Reads the ascii traces written by `rpc-perf` and the twemcache traces
+/// published by Twitter, which have one entry per line:
+/// `timestamp,key,key length,value length,endpoint or client id,op,ttl`
+///
+/// Keys are replayed as they appear in the trace, including any namespace.
+pub struct CsvReader<R> {
+    lines: std::io::Lines<R>,
+}
 
-                // handle writes
-                if event.is_writable() && session.write_pending() > 0 {
-                    session.flush().expect("flush failed");
-                    if session.write_pending() > 0 {
-                        let _ = session.reregister(&self.poll);
-                    }
-                }
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            lines: input.lines(),
+        }
+    }
+}
+
+impl<R: BufRead> TraceReader for CsvReader<R> {
+    fn read(&mut self) -> Option<Entry> {
+        // a line which is not valid UTF-8 is consumed and skipped, any other
+        // error ends the trace
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                return Some(Err("invalid".to_string()));
             }
+            Err(e) => {
+                error!("failed to read trace: {}", e);
+                return None;
+            }
+        };
+        let parts: Vec<&str> = line.split(',').collect();
+        if parts.len() < 7 {
+            return Some(Err("invalid".to_string()));
         }
+
+        let (ts, vlen, ttl) = match (parts[0].parse(), parts[3].parse(), parts[6].parse()) {
+            (Ok(ts), Ok(vlen), Ok(ttl)) => (ts, vlen, ttl),
+            _ => return Some(Err("invalid".to_string())),
+        };
+        let key = parts[1].to_string();
+
+        let record = match parts[5] {
+            "get" => Record::Get { key },
+            "gets" => Record::Gets { key },
+            "set" => Record::Set { key, vlen, ttl },
+            "add" => Record::Add { key, vlen, ttl },
+            "replace" => Record::Replace { key, vlen, ttl },
+            "delete" => Record::Delete { key },
+            op => {
+                return Some(Err(op.to_string()));
+            }
+        };
+        Some(Ok((ts, record)))
     }
 }
 
-pub enum Request {
-    Get { key: String },
-    Gets { key: String },
-    Set { key: String, vlen: usize, ttl: u32 },
-    Add { key: String, vlen: usize, ttl: u32 },
-    Replace { key: String, vlen: usize, ttl: u32 },
-    Delete { key: String },
+/// Reads the binary traces written by `rpc-perf`, which have a 20 byte record
+/// for each entry.
+pub struct BinaryReader<R> {
+    input: R,
 }
 
-#[derive(Clone, Debug, PartialEq)]
-pub enum ParseError {
-    Incomplete,
-    Error,
-    Unknown,
+impl<R: Read> BinaryReader<R> {
+    pub fn new(input: R) -> Self {
+        Self { input }
+    }
 }
 
-// this is a very barebones memcache parser
-fn decode(buffer: &mut Session) -> Result<(), ParseError> {
-    // no-copy borrow as a slice
-    let buf: &[u8] = (*buffer).buffer();
+impl<R: Read> TraceReader for BinaryReader<R> {
+    fn read(&mut self) -> Option<Entry> {
+        let mut tmp = [0_u8; 20];
+        self.input.read_exact(&mut tmp).ok()?;
+
+        let ts: u64 = u32::from_le_bytes([tmp[0], tmp[1], tmp[2], tmp[3]]) as u64;
+        let keyid: u64 = u64::from_le_bytes([
+            tmp[4], tmp[5], tmp[6], tmp[7], tmp[8], tmp[9], tmp[10], tmp[11],
+        ]);
+        let klen_vlen: u32 = u32::from_le_bytes([tmp[12], tmp[13], tmp[14], tmp[15]]);
+        let op_ttl: u32 = u32::from_le_bytes([tmp[16], tmp[17], tmp[18], tmp[19]]);
+        let op: u8 = (op_ttl >> 24) as u8;
+        let ttl: u32 = op_ttl & 0x00FF_FFFF;
+        let klen = klen_vlen >> 22;
+        let vlen: usize = (klen_vlen & 0x003F_FFFF) as usize;
+
+        let key = format!("{:01$}", keyid, klen as usize);
+
+        let record = match op {
+            1 => Record::Get { key },
+            2 => Record::Gets { key },
+            3 => Record::Set { key, vlen, ttl },
+            4 => Record::Add { key, vlen, ttl },
+            6 => Record::Replace { key, vlen, ttl },
+            9 => Record::Delete { key },
+            op => {
+                return Some(Err(format!("op {}", op)));
+            }
+        };
+        Some(Ok((ts, record)))
+    }
+}
 
-    debug!("buffer content: {:?}", buf);
+/// Reads the oracleGeneral traces used by libCacheSim, which have a 24 byte
+/// record for each request: the timestamp (u32), the object id (u64), the
+/// object size (u32), and the time of the next request for the object (i64).
+///
+/// The trace does not record operations. The first request for each object is
+/// replayed as a set, as a cache would be filled after the miss, and later
+/// requests are replayed as gets. The object ids which have been seen are
+/// kept in memory.
+pub struct OracleGeneralReader<R> {
+    input: R,
+    seen: HashSet<u64>,
+}
 
-    for response in &[
-        "STORED\r\n",
-        "NOT_STORED\r\n",
-        "EXISTS\r\n",
-        "NOT_FOUND\r\n",
-        "DELETED\r\n",
-        "TOUCHED\r\n",
-    ] {
-        let bytes = response.as_bytes();
-        if buf.len() >= bytes.len() && &buf[0..bytes.len()] == bytes {
-            let _ = buffer.consume(bytes.len());
-            return Ok(());
+impl<R: Read> OracleGeneralReader<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input,
+            seen: HashSet::new(),
         }
     }
+}
 
-    let mut windows = buf.windows(5);
-    if let Some(response_end) = windows.position(|w| w == b"END\r\n") {
-        if response_end > 0 {
-            RESPONSE_HIT.increment();
+impl<R: Read> TraceReader for OracleGeneralReader<R> {
+    fn read(&mut self) -> Option<Entry> {
+        let mut tmp = [0_u8; 24];
+        self.input.read_exact(&mut tmp).ok()?;
+
+        let ts: u64 = u32::from_le_bytes([tmp[0], tmp[1], tmp[2], tmp[3]]) as u64;
+        let id: u64 = u64::from_le_bytes([
+            tmp[4], tmp[5], tmp[6], tmp[7], tmp[8], tmp[9], tmp[10], tmp[11],
+        ]);
+        let size: usize = u32::from_le_bytes([tmp[12], tmp[13], tmp[14], tmp[15]]) as usize;
+
+        let key = format!("{}", id);
+        let record = if self.seen.insert(id) {
+            Record::Set {
+                key,
+                vlen: size,
+                ttl: 0,
+            }
+        } else {
+            Record::Get { key }
+        };
+        Some(Ok((ts, record)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn readers() {
+        let trace = "0,q:1:abc,7,0,client1,get,0\n1,q:1:abc,7,10,client1,cas,0\n\
+            timestamp,key,key length,value length,client,op,ttl\n2,abc\n";
+        let mut reader = CsvReader::new(trace.as_bytes());
+        let key = "q:1:abc".to_string();
+        assert_eq!(reader.read(), Some(Ok((0, Record::Get { key }))));
+        assert_eq!(reader.read(), Some(Err("cas".to_string())));
+        assert_eq!(reader.read(), Some(Err("invalid".to_string())));
+        assert_eq!(reader.read(), Some(Err("invalid".to_string())));
+        assert_eq!(reader.read(), None);
+
+        // a line which is not valid UTF-8 does not end the trace
+        let trace = b"0,\xff,1,0,client1,get,0\n1,abc,3,0,client1,get,0\n";
+        let mut reader = CsvReader::new(&trace[..]);
+        let key = "abc".to_string();
+        assert_eq!(reader.read(), Some(Err("invalid".to_string())));
+        assert_eq!(reader.read(), Some(Ok((1, Record::Get { key }))));
+        assert_eq!(reader.read(), None);
+
+        let mut trace = Vec::new();
+        for ts in [5_u32, 6] {
+            trace.extend_from_slice(&ts.to_le_bytes());
+            trace.extend_from_slice(&42_u64.to_le_bytes());
+            trace.extend_from_slice(&100_u32.to_le_bytes());
+            trace.extend_from_slice(&(-1_i64).to_le_bytes());
         }
-        let _ = buffer.consume(response_end + 5);
-        return Ok(());
+        let mut reader = OracleGeneralReader::new(&trace[..]);
+        let key = "42".to_string();
+        let set = Record::Set {
+            key: key.clone(),
+            vlen: 100,
+            ttl: 0,
+        };
+        assert_eq!(reader.read(), Some(Ok((5, set))));
+        assert_eq!(reader.read(), Some(Ok((6, Record::Get { key }))));
+        assert_eq!(reader.read(), None);
+    }
+
+    #[test]
+    fn transform() {
+        let set = Record::Set {
+            key: "abc".to_string(),
+            vlen: 10,
+            ttl: 0,
+        };
+        let transform = Transform {
+            amplify: 2,
+            ttl: Some(60),
+            ..Default::default()
+        };
+        let copies: Vec<String> = transform
+            .apply(set)
+            .iter()
+            .map(|r| r.key().to_string())
+            .collect();
+        assert_eq!(copies, ["abc-0", "abc-1"]);
+
+        let transform = Transform {
+            sample: 0.5,
+            ..Default::default()
+        };
+        let keys: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let sampled = keys.iter().filter(|k| transform.sampled(k)).count();
+        assert!(sampled > 400 && sampled < 600);
+
+        // the sample must not change between builds
+        assert_eq!(fnv1a_64(b"a"), 0xaf63dc4c8601ec8c);
     }
 
-    Err(ParseError::Incomplete)
+    #[test]
+    fn compression() {
+        let trace = b"0,abc,3,0,,get,0\n";
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(trace).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let zstd = zstd::encode_all(&trace[..], 0).unwrap();
+
+        for input in [&trace[..], &gzip, &zstd] {
+            let mut output = Vec::new();
+            decompress(input).unwrap().read_to_end(&mut output).unwrap();
+            assert_eq!(output, trace);
+        }
+    }
 }
@@ -0,0 +1,162 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! A protocol-neutral model of the requests sent to the server. Requests are
+//! produced by the `Generator` from the configured keyspaces, or read from a
+//! trace, and are then written to a session by a `Codec`.
+
+use crate::config_file::Verb;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Ping,
+    /// A payload which the server is expected to return unchanged.
+    Echo {
+        value: Vec<u8>,
+    },
+    /// Reads the value for one or more keys.
+    Get {
+        keys: Vec<Vec<u8>>,
+    },
+    /// A get which also returns the compare-and-swap token for each key. Only
+    /// supported by memcache.
+    Gets {
+        keys: Vec<Vec<u8>>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    },
+    /// Stores the value only if the key does not exist. Only supported by
+    /// memcache.
+    Add {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    },
+    /// Stores the value only if the key already exists. Only supported by
+    /// memcache.
+    Replace {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: u32,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Incr {
+        key: Vec<u8>,
+    },
+    Hget {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    },
+    /// Sets each field to the value at the same position. The ttl applies to
+    /// the entire hash and is ignored by protocols which do not support it.
+    Hset {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+        ttl: u32,
+    },
+    Hsetnx {
+        key: Vec<u8>,
+        field: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Hdel {
+        key: Vec<u8>,
+        fields: Vec<Vec<u8>>,
+    },
+    Rpush {
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
+    },
+    Rpushx {
+        key: Vec<u8>,
+        values: Vec<Vec<u8>>,
+    },
+    Count {
+        key: Vec<u8>,
+    },
+    Lrange {
+        key: Vec<u8>,
+    },
+    Ltrim {
+        key: Vec<u8>,
+    },
+}
+
+impl Request {
+    /// The verb which the request is accounted as. Variants which are only
+    /// found in traces are accounted as the closest configurable verb.
+    pub fn verb(&self) -> Verb {
+        match self {
+            Self::Ping => Verb::Ping,
+            Self::Echo { .. } => Verb::Echo,
+            Self::Get { .. } | Self::Gets { .. } => Verb::Get,
+            Self::Set { .. } | Self::Add { .. } | Self::Replace { .. } => Verb::Set,
+            Self::Delete { .. } => Verb::Delete,
+            Self::Incr { .. } => Verb::Incr,
+            Self::Hget { .. } => Verb::Hget,
+            Self::Hset { .. } => Verb::Hset,
+            Self::Hsetnx { .. } => Verb::Hsetnx,
+            Self::Hdel { .. } => Verb::Hdel,
+            Self::Rpush { .. } => Verb::Rpush,
+            Self::Rpushx { .. } => Verb::Rpushx,
+            Self::Count { .. } => Verb::Count,
+            Self::Lrange { .. } => Verb::Lrange,
+            Self::Ltrim { .. } => Verb::Ltrim,
+        }
+    }
+
+    /// Returns the key the request is routed by. For a multi-get, this is the
+    /// first key.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Self::Ping | Self::Echo { .. } => None,
+            Self::Get { keys } | Self::Gets { keys } => keys.first().map(|k| k.as_slice()),
+            Self::Set { key, .. }
+            | Self::Add { key, .. }
+            | Self::Replace { key, .. }
+            | Self::Delete { key }
+            | Self::Incr { key }
+            | Self::Hget { key, .. }
+            | Self::Hset { key, .. }
+            | Self::Hsetnx { key, .. }
+            | Self::Hdel { key, .. }
+            | Self::Rpush { key, .. }
+            | Self::Rpushx { key, .. }
+            | Self::Count { key }
+            | Self::Lrange { key }
+            | Self::Ltrim { key } => Some(key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key() {
+        let request = Request::Get {
+            keys: vec![b"a".to_vec(), b"b".to_vec()],
+        };
+        assert_eq!(request.key(), Some(&b"a"[..]));
+        assert_eq!(request.verb(), Verb::Get);
+
+        let request = Request::Replace {
+            key: b"c".to_vec(),
+            value: Vec::new(),
+            ttl: 0,
+        };
+        assert_eq!(request.key(), Some(&b"c"[..]));
+        assert_eq!(request.verb(), Verb::Set);
+
+        assert_eq!(Request::Get { keys: Vec::new() }.key(), None);
+        assert_eq!(Request::Ping.key(), None);
+    }
+}
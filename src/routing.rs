@@ -0,0 +1,169 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Consistent hashing of keys onto a fixed list of endpoints. This matches how
+//! sharded caches are typically accessed through clients and proxies such as
+//! twemproxy, so that each key is always sent to the same endpoint. Requests
+//! with multiple keys are routed by their first key.
+
+use crate::config_file::Routing;
+use std::net::SocketAddr;
+
+/// the number of points on the continuum for each endpoint
+const KETAMA_POINTS: usize = 160;
+
+/// Maps keys onto the endpoints.
+pub enum Router {
+    Ketama(Ketama),
+    Jump(Jump),
+}
+
+impl Router {
+    /// Create a new `Router` for the routing mode. Returns `None` if the mode
+    /// does not use a fixed list of endpoints.
+    pub fn new(routing: Routing, endpoints: Vec<SocketAddr>) -> Option<Self> {
+        match routing {
+            Routing::Ketama => Some(Self::Ketama(Ketama::new(endpoints))),
+            Routing::Jump => Some(Self::Jump(Jump::new(endpoints))),
+            Routing::RedisCluster => None,
+        }
+    }
+
+    /// Returns the endpoint for the key.
+    pub fn route(&self, key: &[u8]) -> SocketAddr {
+        match self {
+            Self::Ketama(ketama) => ketama.route(key),
+            Self::Jump(jump) => jump.route(key),
+        }
+    }
+}
+
+/// Ketama consistent hashing, compatible with libmemcached and twemproxy when
+/// all endpoints have equal weight. Each endpoint is placed at several points
+/// on a continuum using the md5 of `ip:port-index`, and a key is served by the
+/// endpoint at the next point following the md5 of the key.
+pub struct Ketama {
+    continuum: Vec<(u32, SocketAddr)>,
+}
+
+impl Ketama {
+    pub fn new(endpoints: Vec<SocketAddr>) -> Self {
+        let mut continuum = Vec::with_capacity(endpoints.len() * KETAMA_POINTS);
+        for endpoint in endpoints {
+            // each digest provides four points
+            for index in 0..(KETAMA_POINTS / 4) {
+                let digest = md5::compute(format!("{}-{}", endpoint, index));
+                for point in 0..4 {
+                    continuum.push((ketama_hash(&digest, point), endpoint));
+                }
+            }
+        }
+        continuum.sort_by_key(|(point, _)| *point);
+        Self { continuum }
+    }
+
+    pub fn route(&self, key: &[u8]) -> SocketAddr {
+        let hash = ketama_hash(&md5::compute(key), 0);
+        let index = self.continuum.partition_point(|(point, _)| *point < hash);
+        if index == self.continuum.len() {
+            self.continuum[0].1
+        } else {
+            self.continuum[index].1
+        }
+    }
+}
+
+/// Takes the four bytes of the digest at the offset as a little endian value.
+fn ketama_hash(digest: &[u8; 16], offset: usize) -> u32 {
+    let bytes = &digest[(offset * 4)..(offset * 4 + 4)];
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Jump consistent hashing, as described by Lamping and Veach. The key is
+/// hashed with 64bit FNV-1a and mapped onto the endpoints in the order they
+/// are configured. Adding an endpoint to the end of the list only moves the
+/// keys which are now served by the new endpoint.
+pub struct Jump {
+    endpoints: Vec<SocketAddr>,
+}
+
+impl Jump {
+    pub fn new(endpoints: Vec<SocketAddr>) -> Self {
+        Self { endpoints }
+    }
+
+    pub fn route(&self, key: &[u8]) -> SocketAddr {
+        self.endpoints[jump_hash(fnv1a_64(key), self.endpoints.len())]
+    }
+}
+
+fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1_u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+fn fnv1a_64(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn endpoints(count: u16) -> Vec<SocketAddr> {
+        (0..count)
+            .map(|i| SocketAddr::from(([127, 0, 0, 1], 11211 + i)))
+            .collect()
+    }
+
+    #[test]
+    fn ketama() {
+        let ketama = Ketama::new(endpoints(4));
+        assert_eq!(ketama.continuum.len(), 4 * KETAMA_POINTS);
+
+        // adding an endpoint only moves keys onto the new endpoint
+        let grown = Ketama::new(endpoints(5));
+        let added = endpoints(5)[4];
+        for i in 0..1000 {
+            let key = format!("key:{}", i);
+            let before = ketama.route(key.as_bytes());
+            let after = grown.route(key.as_bytes());
+            assert!(before == after || after == added);
+        }
+    }
+
+    #[test]
+    fn jump() {
+        assert_eq!(fnv1a_64(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a_64(b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(jump_hash(0, 1), 0);
+
+        let jump = Jump::new(endpoints(4));
+        let grown = Jump::new(endpoints(5));
+        let added = endpoints(5)[4];
+        let mut moved = 0;
+        for i in 0..1000 {
+            let key = format!("key:{}", i);
+            let before = jump.route(key.as_bytes());
+            let after = grown.route(key.as_bytes());
+            if before != after {
+                assert_eq!(after, added);
+                moved += 1;
+            }
+        }
+        // roughly one fifth of the keys move to the new endpoint
+        assert!(moved > 100 && moved < 300);
+    }
+}
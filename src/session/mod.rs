@@ -10,11 +10,13 @@ mod buffer;
 mod stream;
 mod tcp_stream;
 
+use crate::config_file::Verb;
 use crate::metrics::*;
 use crate::*;
 use boring::ssl::SslSession;
 
 use std::borrow::{Borrow, BorrowMut};
+use std::collections::VecDeque;
 use std::io::{BufRead, ErrorKind, Read, Write};
 use std::net::SocketAddr;
 
@@ -27,6 +29,29 @@ use stream::Stream;
 
 pub use tcp_stream::TcpStream;
 
+/// A request which has been written to a session and is waiting for its
+/// response.
+#[derive(Clone, Debug)]
+pub struct InFlight {
+    /// the time the request should have been sent, latency is measured from
+    /// this time
+    pub start: Instant,
+    /// the time by which the response must be received
+    pub deadline: Option<Instant>,
+    /// the command, which is not set for requests generated internally
+    pub verb: Option<Verb>,
+    /// the index of the keyspace the request was generated from
+    pub keyspace: Option<usize>,
+    /// the keys of the request, which are only kept when values are verified
+    pub keys: Vec<Vec<u8>>,
+    /// for a write, the version which was written, and for a lookup, the
+    /// oldest version of each key which may be returned
+    pub versions: Vec<u64>,
+    /// indicates the reply to `ASKING` precedes the response, which is the
+    /// case for a request redirected by `ASK`
+    pub asking: bool,
+}
+
 pub struct Session {
     token: Token,
     stream: Stream,
@@ -37,10 +62,19 @@ pub struct Session {
     connected: bool,
     // hold current interest set
     interest: Interest,
-    /// A timestamp which is used to calculate response latency
+    /// A timestamp which is used to calculate connect latency
     timestamp: Instant,
-    /// the number of outstanding responses
-    outstanding: usize,
+    /// the requests which are waiting for a response, oldest first
+    inflight: VecDeque<InFlight>,
+    /// the number of outstanding responses to connection setup commands
+    setup: usize,
+    /// the endpoint the session was connected to
+    endpoint: Option<SocketAddr>,
+    /// the time by which the session must connect or receive a response
+    deadline: Option<Instant>,
+    /// the work queue the session takes its requests from, if the session
+    /// has its own queue
+    shard: Option<usize>,
 }
 
 impl std::fmt::Debug for Session {
@@ -95,7 +129,11 @@ impl Session {
             max_capacity,
             interest: Interest::WRITABLE,
             timestamp: Instant::now(),
-            outstanding: 0,
+            inflight: VecDeque::new(),
+            setup: 0,
+            endpoint: None,
+            deadline: None,
+            shard: None,
         }
     }
 
@@ -181,6 +219,8 @@ impl Session {
 
     /// Closes the session and the underlying stream.
     pub fn close(&mut self) {
+        self.deadline = None;
+        self.inflight.clear();
         self.stream.close();
     }
 
@@ -211,10 +251,34 @@ impl Session {
         self.read_buffer.borrow()
     }
 
+    /// Returns a reference to the data in the write buffer which has not yet
+    /// been flushed.
+    pub fn write_buffer(&self) -> &[u8] {
+        self.write_buffer.borrow()
+    }
+
     pub fn peer_addr(&self) -> Result<SocketAddr, std::io::Error> {
         self.stream.peer_addr()
     }
 
+    pub fn set_endpoint(&mut self, endpoint: SocketAddr) {
+        self.endpoint = Some(endpoint);
+    }
+
+    /// Returns the endpoint the session was connected to. Unlike `peer_addr`
+    /// this does not require the session to be connected.
+    pub fn endpoint(&self) -> Option<SocketAddr> {
+        self.endpoint
+    }
+
+    pub fn set_shard(&mut self, shard: Option<usize>) {
+        self.shard = shard;
+    }
+
+    pub fn shard(&self) -> Option<usize> {
+        self.shard
+    }
+
     pub fn timestamp(&self) -> Instant {
         self.timestamp
     }
@@ -227,12 +291,56 @@ impl Session {
         self.stream.ssl_session()
     }
 
-    pub fn set_outstanding(&mut self, count: usize) {
-        self.outstanding = count;
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Returns the time by which the session must finish connecting or
+    /// receive the outstanding responses.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
     }
 
+    /// Records a request which has been written to the session.
+    pub fn push_request(&mut self, request: InFlight) {
+        self.inflight.push_back(request);
+    }
+
+    /// Removes the oldest request once its response has been received.
+    pub fn pop_request(&mut self) -> Option<InFlight> {
+        self.inflight.pop_front()
+    }
+
+    /// Returns the oldest request which is waiting for a response.
+    pub fn next_request(&self) -> Option<&InFlight> {
+        self.inflight.front()
+    }
+
+    /// Returns the oldest request which is waiting for a response, so that it
+    /// can be updated.
+    pub fn next_request_mut(&mut self) -> Option<&mut InFlight> {
+        self.inflight.front_mut()
+    }
+
+    /// Removes all the requests which are waiting for a response.
+    pub fn take_requests(&mut self) -> VecDeque<InFlight> {
+        std::mem::take(&mut self.inflight)
+    }
+
+    /// Returns the number of requests which are waiting for a response.
     pub fn outstanding(&self) -> usize {
-        self.outstanding
+        self.inflight.len()
+    }
+
+    pub fn set_setup(&mut self, count: usize) {
+        self.setup = count;
+    }
+
+    /// Returns the number of responses to connection setup commands which
+    /// have not yet been received. The session is not ready for requests until
+    /// this reaches zero.
+    pub fn setup(&self) -> usize {
+        self.setup
     }
 }
 
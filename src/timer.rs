@@ -0,0 +1,104 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! A hashed timer wheel which is used to find sessions with expired deadlines
+//! without scanning every session.
+
+use crate::*;
+use mio::Token;
+
+/// A timer wheel with a fixed number of slots, each covering one tick. Timers
+/// which are further out than one rotation of the wheel remain in their slot
+/// until their tick is reached.
+///
+/// Timers can not be cancelled. Instead, the owner should check that a timer
+/// which has fired is still relevant, such as by comparing the deadline held
+/// by the session.
+pub struct TimerWheel {
+    start: Instant,
+    /// the length of each tick in nanoseconds
+    resolution: u64,
+    /// the next tick which has not been processed
+    tick: u64,
+    slots: Vec<Vec<(u64, Token)>>,
+}
+
+impl TimerWheel {
+    /// Create a new `TimerWheel` with ticks of the provided resolution.
+    pub fn new(resolution: Duration, slots: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            resolution: resolution.as_nanos().max(1),
+            tick: 0,
+            slots: vec![Vec::new(); slots],
+        }
+    }
+
+    /// Adds a timer for the token which fires once the deadline has passed.
+    pub fn insert(&mut self, token: Token, deadline: Instant) {
+        // round up so that the timer never fires before the deadline
+        let nanos = (deadline - self.start).as_nanos();
+        let tick = nanos.div_ceil(self.resolution).max(self.tick);
+        let slot = (tick % self.slots.len() as u64) as usize;
+        self.slots[slot].push((tick, token));
+    }
+
+    /// Removes and returns the tokens for all timers which have fired.
+    pub fn expire(&mut self, now: Instant) -> Vec<Token> {
+        let current = (now - self.start).as_nanos() / self.resolution;
+        let mut expired = Vec::new();
+        if current < self.tick {
+            return expired;
+        }
+
+        // each slot only needs to be visited once, even if more than one
+        // rotation has elapsed
+        let len = self.slots.len() as u64;
+        let count = (current - self.tick + 1).min(len);
+        for offset in 0..count {
+            let slot = ((self.tick + offset) % len) as usize;
+            self.slots[slot].retain(|(tick, token)| {
+                if *tick <= current {
+                    expired.push(*token);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.tick = current + 1;
+
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expire() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(1), 8);
+        let start = wheel.start;
+
+        wheel.insert(Token(1), start + Duration::from_millis(2));
+        wheel.insert(Token(2), start + Duration::from_millis(5));
+        // more than one rotation away
+        wheel.insert(Token(3), start + Duration::from_millis(20));
+
+        assert!(wheel.expire(start + Duration::from_millis(1)).is_empty());
+        assert_eq!(
+            wheel.expire(start + Duration::from_millis(2)),
+            vec![Token(1)]
+        );
+        assert_eq!(
+            wheel.expire(start + Duration::from_millis(13)),
+            vec![Token(2)]
+        );
+        assert_eq!(
+            wheel.expire(start + Duration::from_millis(100)),
+            vec![Token(3)]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2023 Twitter, Inc.
+// Licensed under the Apache License, Version 2.0
+// http://www.apache.org/licenses/LICENSE-2.0
+
+//! Records the generated requests to a zstd compressed trace which can be read
+//! by `rpc-replay`.
+//!
+//! The ascii format has one line per key:
+//! `timestamp,key,key length,value length,endpoint,verb,ttl`
+//!
+//! The binary format has a 20 byte little-endian record per key: the timestamp
+//! (u32), the key as a number (u64), the key length in the top 10 bits and the
+//! value length in the lower 22 bits (u32), then the opcode in the top 8 bits
+//! and the ttl in the lower 24 bits (u32). Keys which are not numeric are
+//! replaced by a hash of the key with the same number of digits, and requests
+//! which have no opcode are not recorded. The endpoint is not recorded.
+//!
+//! Timestamps are in seconds since the start of the run.
+
+use crate::config_file::TraceFormat;
+use crate::request::Request;
+use crate::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use zstd::Encoder;
+
+/// Records are buffered by each worker and written in batches of this size
+const BATCH_SIZE: usize = 64 * 1024;
+
+/// The trace file which is shared between the workers.
+pub struct Trace {
+    format: TraceFormat,
+    start: Instant,
+    encoder: Mutex<Option<Encoder<'static, BufWriter<File>>>>,
+    /// the buffer of each worker, which are written out when the trace is
+    /// finished
+    buffers: Mutex<Vec<Arc<Mutex<Vec<u8>>>>>,
+}
+
+impl Trace {
+    /// Opens the trace file if one is configured.
+    pub fn new(config: &Config) -> Option<Self> {
+        let output = config.trace().output()?;
+        let file = match File::create(&output) {
+            Ok(file) => file,
+            Err(e) => {
+                fatal!("failed to create trace file: {}: {}", output, e);
+            }
+        };
+        let encoder = Encoder::new(BufWriter::new(file), 0).expect("failed to init zstd encoder");
+        info!("trace: {}", output);
+        Some(Self {
+            format: config.trace().format(),
+            start: Instant::now(),
+            encoder: Mutex::new(Some(encoder)),
+            buffers: Mutex::new(Vec::new()),
+        })
+    }
+
+    fn write(&self, records: &[u8]) {
+        let mut encoder = self.encoder.lock().unwrap();
+        if let Some(ref mut e) = *encoder {
+            if let Err(e) = e.write_all(records) {
+                error!("failed to write trace: {}", e);
+                *encoder = None;
+            }
+        }
+    }
+
+    /// Completes the trace, including the records which are still buffered
+    /// by the workers. Any records which are written afterwards are discarded.
+    pub fn finish(&self) {
+        for buffer in self.buffers.lock().unwrap().iter() {
+            let mut buffer = buffer.lock().unwrap();
+            self.write(&buffer);
+            buffer.clear();
+        }
+        if let Some(encoder) = self.encoder.lock().unwrap().take() {
+            if let Err(e) = encoder.finish().and_then(|mut f| f.flush()) {
+                error!("failed to finish trace: {}", e);
+            }
+        }
+    }
+}
+
+/// Buffers the records for a single worker. The buffer is shared with the
+/// trace so that it can be written out when the trace is finished, and is
+/// otherwise only locked by the worker.
+pub struct TraceWriter {
+    trace: Arc<Trace>,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    flushed: Instant,
+}
+
+impl TraceWriter {
+    pub fn new(trace: Arc<Trace>) -> Self {
+        let buffer = Arc::new(Mutex::new(Vec::with_capacity(BATCH_SIZE)));
+        trace.buffers.lock().unwrap().push(buffer.clone());
+        Self {
+            trace,
+            buffer,
+            flushed: Instant::now(),
+        }
+    }
+
+    /// Records a request which was sent to the endpoint. A request for
+    /// multiple keys is recorded once for each key. The buffer is written to
+    /// the trace when it is full.
+    pub fn record(&mut self, request: &Request, endpoint: Option<SocketAddr>) {
+        let timestamp = (Instant::now() - self.trace.start).as_secs();
+        let mut buffer = self.buffer.lock().unwrap();
+        match self.trace.format {
+            TraceFormat::Ascii => ascii(&mut buffer, timestamp, request, endpoint),
+            TraceFormat::Binary => binary(&mut buffer, timestamp, request),
+        }
+        if buffer.len() >= BATCH_SIZE {
+            self.trace.write(&buffer);
+            buffer.clear();
+            self.flushed = Instant::now();
+        }
+    }
+
+    /// Writes the buffer to the trace if it has not been written for a
+    /// second. This is called from the event loop so that records are not
+    /// held back while there are few requests.
+    pub fn tick(&mut self) {
+        let now = Instant::now();
+        if now - self.flushed < Duration::from_secs(1) {
+            return;
+        }
+        self.flushed = now;
+        let mut buffer = self.buffer.lock().unwrap();
+        if !buffer.is_empty() {
+            self.trace.write(&buffer);
+            buffer.clear();
+        }
+    }
+}
+
+/// Returns the keys of the request along with the length of the value and the
+/// ttl.
+fn fields(request: &Request) -> (Vec<&[u8]>, usize, u32) {
+    match request {
+        Request::Get { keys } | Request::Gets { keys } => {
+            (keys.iter().map(|k| k.as_slice()).collect(), 0, 0)
+        }
+        Request::Set { key, value, ttl }
+        | Request::Add { key, value, ttl }
+        | Request::Replace { key, value, ttl } => (vec![key], value.len(), *ttl),
+        Request::Hset {
+            key, values, ttl, ..
+        } => (vec![key], values.iter().map(|v| v.len()).sum(), *ttl),
+        Request::Hsetnx { key, value, .. } => (vec![key], value.len(), 0),
+        Request::Rpush { key, values } | Request::Rpushx { key, values } => {
+            (vec![key], values.iter().map(|v| v.len()).sum(), 0)
+        }
+        Request::Echo { value } => (Vec::new(), value.len(), 0),
+        request => (request.key().into_iter().collect(), 0, 0),
+    }
+}
+
+fn name(request: &Request) -> String {
+    match request {
+        Request::Gets { .. } => "gets".to_string(),
+        Request::Add { .. } => "add".to_string(),
+        Request::Replace { .. } => "replace".to_string(),
+        request => request.verb().as_ref().to_string(),
+    }
+}
+
+fn ascii(buffer: &mut Vec<u8>, timestamp: u64, request: &Request, endpoint: Option<SocketAddr>) {
+    let (keys, vlen, ttl) = fields(request);
+    let endpoint = endpoint.map(|e| e.to_string()).unwrap_or_default();
+    let name = name(request);
+    for key in keys {
+        let _ = writeln!(
+            buffer,
+            "{},{},{},{},{},{},{}",
+            timestamp,
+            String::from_utf8_lossy(key),
+            key.len(),
+            vlen,
+            endpoint,
+            name,
+            ttl
+        );
+    }
+}
+
+fn binary(buffer: &mut Vec<u8>, timestamp: u64, request: &Request) {
+    let op: u32 = match request {
+        Request::Get { .. } => 1,
+        Request::Gets { .. } => 2,
+        Request::Set { .. } => 3,
+        Request::Add { .. } => 4,
+        Request::Replace { .. } => 6,
+        Request::Delete { .. } => 9,
+        Request::Incr { .. } => 10,
+        _ => {
+            return;
+        }
+    };
+    let (keys, vlen, ttl) = fields(request);
+    for key in keys {
+        let klen = key.len().min(0x3FF) as u32;
+        let vlen = vlen.min(0x003F_FFFF) as u32;
+        let ttl = ttl.min(0x00FF_FFFF);
+        buffer.extend_from_slice(&(timestamp as u32).to_le_bytes());
+        buffer.extend_from_slice(&key_id(key).to_le_bytes());
+        buffer.extend_from_slice(&((klen << 22) | vlen).to_le_bytes());
+        buffer.extend_from_slice(&((op << 24) | ttl).to_le_bytes());
+    }
+}
+
+/// Returns the key as a number. Keys which are not numeric are hashed and
+/// reduced to the same number of digits as the key, so the replayed key has
+/// the same length and each key is replayed as the same key.
+fn key_id(key: &[u8]) -> u64 {
+    if !key.is_empty() && key.iter().all(|b| b.is_ascii_digit()) {
+        if let Some(id) = std::str::from_utf8(key)
+            .ok()
+            .and_then(|k| k.parse::<u64>().ok())
+        {
+            return id;
+        }
+    }
+
+    // fnv-1a
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    match 10_u64.checked_pow(key.len() as u32) {
+        Some(limit) => hash % limit,
+        None => hash,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let request = Request::Get {
+            keys: vec![b"abc".to_vec(), b"0042".to_vec()],
+        };
+        let endpoint = Some("127.0.0.1:11211".parse().unwrap());
+        let mut buffer = Vec::new();
+        ascii(&mut buffer, 7, &request, endpoint);
+        assert_eq!(
+            buffer,
+            b"7,abc,3,0,127.0.0.1:11211,get,0\n7,0042,4,0,127.0.0.1:11211,get,0\n"
+        );
+
+        let request = Request::Set {
+            key: b"0042".to_vec(),
+            value: vec![0; 100],
+            ttl: 300,
+        };
+        let mut buffer = Vec::new();
+        binary(&mut buffer, 7, &request);
+        assert_eq!(buffer.len(), 20);
+        assert_eq!(&buffer[0..4], &7_u32.to_le_bytes());
+        assert_eq!(&buffer[4..12], &42_u64.to_le_bytes());
+        assert_eq!(&buffer[12..16], &((4_u32 << 22) | 100).to_le_bytes());
+        assert_eq!(&buffer[16..20], &((3_u32 << 24) | 300).to_le_bytes());
+
+        // requests without an opcode are skipped
+        let mut buffer = Vec::new();
+        binary(&mut buffer, 7, &Request::Ping);
+        assert!(buffer.is_empty());
+
+        // hashed keys keep their length
+        assert!(key_id(b"abc") < 1000);
+        assert_eq!(key_id(b"abc"), key_id(b"abc"));
+    }
+}
@@ -2,14 +2,22 @@
 // Licensed under the Apache License, Version 2.0
 // http://www.apache.org/licenses/LICENSE-2.0
 
+use crate::breakdown::Breakdown;
+use crate::cluster::*;
 use crate::codec::*;
-use crate::config_file::Tls;
+use crate::config::Stream;
+use crate::config_file::{Routing, Tls, Verb};
+use crate::generator::Generator;
+use crate::integrity::{self, Check, Freshness, Versions};
 use crate::metrics::*;
-use crate::session::TcpStream;
+use crate::routing::Router;
+use crate::session::{InFlight, TcpStream};
+use crate::timer::TimerWheel;
+use crate::trace::{Trace, TraceWriter};
 use crate::*;
 use boring::x509::X509;
+use mpmc::Queue;
 use rand::seq::SliceRandom;
-use rand::thread_rng;
 use rustcommon_heatmap::AtomicHeatmap;
 use rustcommon_heatmap::AtomicU64;
 use rustcommon_ratelimiter::Ratelimiter;
@@ -28,6 +36,10 @@ use std::sync::Arc;
 
 pub struct Worker {
     codec: Box<dyn Codec>,
+    generator: Generator,
+    /// a work queue for each session, which preserves the order of the
+    /// requests in each queue. empty unless set by `set_session_queues`
+    shards: Vec<Shard>,
     connect_queue: VecDeque<(SocketAddr, Option<SslSession>)>,
     connect_ratelimit: Option<Arc<Ratelimiter>>,
     poll: Poll,
@@ -39,11 +51,49 @@ pub struct Worker {
     connect_heatmap: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
     request_heatmap: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
     request_waterfall: Option<Arc<AtomicHeatmap<u64, AtomicU64>>>,
+    breakdown: Option<Arc<Breakdown>>,
+    trace: Option<TraceWriter>,
     pipeline: usize,
+    poolsize: usize,
+    cluster: Option<Cluster>,
+    router: Option<Router>,
+    timers: TimerWheel,
+    connect_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    /// latency in microseconds recorded for each timed out request
+    timeout_penalty: Option<u64>,
+    open_loop: bool,
+    /// the versions of the keys, which are only tracked when values are
+    /// verified
+    versions: Option<Arc<Versions>>,
+    /// the intended send times of requests which are waiting for a session
+    schedule: VecDeque<Instant>,
+    /// the last time the request ratelimiter was drained
+    scheduled: Option<Instant>,
+    /// the number of queued requests which have been counted as delayed
+    delayed: usize,
+    /// the time since which routed requests have been waiting for a session
+    /// to their endpoint
+    blocked: Option<Instant>,
+}
+
+/// The most requests which may wait for a session in open-loop mode
+const MAX_SCHEDULED: usize = 1024 * 1024;
+
+/// How long a routed request waits for a session to its endpoint before it
+/// is dropped, if there is no request timeout
+const ROUTE_WAIT: Duration = Duration::from_secs(1);
+
+/// A work queue along with the endpoint its requests are sent to and the
+/// session which is bound to it.
+struct Shard {
+    endpoint: SocketAddr,
+    generator: Generator,
+    token: Option<Token>,
 }
 
 impl Worker {
-    pub fn new(config: Arc<Config>) -> Result<Self, std::io::Error> {
+    pub fn new(config: Arc<Config>, id: usize) -> Result<Self, std::io::Error> {
         let poll = mio::Poll::new().unwrap();
 
         let connections = config.connection().poolsize() * config.endpoints().len();
@@ -61,7 +111,7 @@ impl Worker {
 
         // shuffle connect queue
         let mut tmp: Vec<(SocketAddr, Option<SslSession>)> = connect_queue.drain(0..).collect();
-        let mut rng = thread_rng();
+        let mut rng = config.rng(id, Stream::Connect);
         tmp.shuffle(&mut rng);
         for addr in tmp {
             connect_queue.push_back(addr);
@@ -76,14 +126,28 @@ impl Worker {
 
         // initialize the codec
         let codec = match config.general().protocol() {
-            Protocol::Ping => Box::new(Ping::new(config.clone())) as Box<dyn Codec>,
-            Protocol::Echo => Box::new(Echo::new(config.clone())) as Box<dyn Codec>,
-            Protocol::Memcache => Box::new(Memcache::new(config.clone())) as Box<dyn Codec>,
-            Protocol::Redis | Protocol::RedisInline | Protocol::RedisResp => {
-                Box::new(Redis::new(config.clone())) as Box<dyn Codec>
+            Protocol::Ping => Box::new(Ping::new()) as Box<dyn Codec>,
+            Protocol::Echo => Box::new(Echo::new()) as Box<dyn Codec>,
+            Protocol::Memcache => Box::new(Memcache::new()) as Box<dyn Codec>,
+            Protocol::MemcacheBinary => {
+                Box::new(MemcacheBinary::new(config.clone())) as Box<dyn Codec>
             }
-            Protocol::ThriftCache => Box::new(ThriftCache::new(config.clone())) as Box<dyn Codec>,
+            Protocol::MemcacheMeta => Box::new(MemcacheMeta::new(config.clone())) as Box<dyn Codec>,
+            Protocol::Redis
+            | Protocol::RedisInline
+            | Protocol::RedisResp
+            | Protocol::RedisResp3 => Box::new(Redis::new(config.clone())) as Box<dyn Codec>,
+            Protocol::ThriftCache => Box::new(ThriftCache::new()) as Box<dyn Codec>,
+        };
+
+        let cluster = if config.routing() == Some(Routing::RedisCluster) {
+            Some(Cluster::new(config.endpoints()))
+        } else {
+            None
         };
+        let router = config
+            .routing()
+            .and_then(|routing| Router::new(routing, config.endpoints()));
 
         // return the worker
         Ok(Worker {
@@ -96,10 +160,36 @@ impl Worker {
             sessions,
             tls,
             codec,
+            generator: Generator::new(config.clone(), id),
+            shards: Vec::new(),
             connect_heatmap: None,
             request_heatmap: None,
             request_waterfall: None,
+            breakdown: None,
+            trace: None,
             pipeline,
+            poolsize: config.connection().poolsize(),
+            cluster,
+            router,
+            timers: TimerWheel::new(Duration::from_millis(1), 1024),
+            connect_timeout: config
+                .connection()
+                .timeout()
+                .map(|ms| Duration::from_millis(ms as u64)),
+            request_timeout: config
+                .request()
+                .timeout()
+                .map(|ms| Duration::from_millis(ms as u64)),
+            timeout_penalty: config
+                .request()
+                .timeout_penalty()
+                .map(|ms| ms as u64 * 1_000),
+            open_loop: config.request().open_loop(),
+            versions: None,
+            schedule: VecDeque::new(),
+            scheduled: None,
+            delayed: 0,
+            blocked: None,
         })
     }
 
@@ -135,6 +225,43 @@ impl Worker {
         self.request_waterfall = heatmap;
     }
 
+    /// Provide the per-verb and per-keyspace metrics
+    pub fn set_breakdown(&mut self, breakdown: Option<Arc<Breakdown>>) {
+        self.breakdown = breakdown;
+    }
+
+    pub fn set_versions(&mut self, versions: Option<Arc<Versions>>) {
+        self.generator.set_versions(versions.clone());
+        self.versions = versions;
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Arc<Trace>>) {
+        self.trace = trace.map(TraceWriter::new);
+    }
+
+    /// Send the requests from a queue which is shared with other workers
+    /// instead of generating them from the keyspaces in the config.
+    pub fn set_work_queue(&mut self, queue: Queue<Request>) {
+        self.generator = Generator::from_queue(queue);
+    }
+
+    /// Send the requests from a queue for each session. Each queue is only
+    /// used by one session at a time, so its requests are sent in order. When
+    /// a session is closed, its queue is taken over by the next session to
+    /// connect to the same endpoint. The requests have already been routed to
+    /// the queues for their endpoint, so they are not routed again.
+    pub fn set_session_queues(&mut self, queues: Vec<(SocketAddr, Queue<Request>)>) {
+        self.router = None;
+        self.shards = queues
+            .into_iter()
+            .map(|(endpoint, queue)| Shard {
+                endpoint,
+                generator: Generator::from_queue(queue),
+                token: None,
+            })
+            .collect();
+    }
+
     /// Internal function to connect the session
     fn connect(
         &mut self,
@@ -142,6 +269,9 @@ impl Worker {
         ssl_session: Option<SslSession>,
     ) -> Result<Token, std::io::Error> {
         CONNECT.increment();
+        if let Some(ref breakdown) = self.breakdown {
+            breakdown.connect(Some(addr));
+        }
         let stream = TcpStream::connect(addr)?;
         let mut session = if let Some(tls) = &self.tls {
             if let Ok(mut connect_config) = tls.configure() {
@@ -180,8 +310,19 @@ impl Worker {
         let entry = self.sessions.vacant_entry();
         let token = Token(entry.key());
         session.set_token(token);
-        session.set_timestamp(Instant::now());
+        session.set_endpoint(addr);
+        if let Some(shard) = self
+            .shards
+            .iter()
+            .position(|s| s.token.is_none() && s.endpoint == addr)
+        {
+            self.shards[shard].token = Some(token);
+            session.set_shard(Some(shard));
+        }
+        let now = Instant::now();
+        session.set_timestamp(now);
         entry.insert(session);
+        self.set_deadline(token, self.connect_timeout, now)?;
         Ok(token)
     }
 
@@ -190,15 +331,96 @@ impl Worker {
         OPEN.decrement();
         let session = get_session_mut!(self, token)?;
         let _ = session.deregister(&self.poll);
-        let peer_addr = session.peer_addr();
+        let endpoint = session.endpoint();
         let ssl_session = session.ssl_session();
+        if let Some(shard) = session.shard() {
+            if self.shards[shard].token == Some(token) {
+                self.shards[shard].token = None;
+            }
+        }
         session.close();
-        if let Ok(addr) = peer_addr {
+        if let Some(ref mut cluster) = self.cluster {
+            cluster.closed(token);
+        }
+        if let Some(addr) = endpoint {
             self.connect_queue.push_back((addr, ssl_session));
         }
         Ok(())
     }
 
+    /// Sets the deadline for the session to connect or to receive responses.
+    /// A timeout of `None` clears the deadline.
+    fn set_deadline(
+        &mut self,
+        token: Token,
+        timeout: Option<Duration>,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let session = get_session_mut!(self, token)?;
+        let deadline = timeout.map(|timeout| now + timeout);
+        session.set_deadline(deadline);
+        if let Some(deadline) = deadline {
+            self.timers.insert(token, deadline);
+        }
+        Ok(())
+    }
+
+    /// Closes any sessions which have not connected or received their
+    /// responses before their deadline. Timed out requests are counted and,
+    /// if configured, recorded in the latency distribution with the timeout
+    /// penalty. The sessions are then reconnected.
+    fn expire_timeouts(&mut self) {
+        let now = Instant::now();
+        for token in self.timers.expire(now) {
+            // timers are not cancelled, so check the session is still waiting
+            let session = match self.sessions.get_mut(token.0) {
+                Some(session) => session,
+                None => continue,
+            };
+            match session.deadline() {
+                Some(deadline) if deadline <= now => {}
+                _ => continue,
+            }
+            let endpoint = session.endpoint();
+            // a session which times out before it is ready for requests, which
+            // includes the tls handshake and setup commands such as `AUTH`,
+            // `HELLO`, or SASL, is counted as a connect timeout
+            if session.is_connecting() || session.is_handshaking() || session.setup() > 0 {
+                CONNECT_TIMEOUT.increment();
+                if let Some(ref breakdown) = self.breakdown {
+                    breakdown.connect_timeout(endpoint);
+                }
+            }
+            if !session.is_connecting() {
+                let requests = session.take_requests();
+                if let Some(ref breakdown) = self.breakdown {
+                    for request in &requests {
+                        breakdown.timeout(request, endpoint, now, self.timeout_penalty);
+                    }
+                }
+                let outstanding = requests.len() as u64;
+                REQUEST_TIMEOUT.add(outstanding);
+                if let Some(penalty) = self.timeout_penalty {
+                    if let Some(ref heatmap) = self.request_heatmap {
+                        heatmap.increment(now, penalty, outstanding);
+                    }
+                    if let Some(ref waterfall) = self.request_waterfall {
+                        waterfall.increment(now, penalty * 1_000, outstanding);
+                    }
+                }
+            }
+            let _ = self.disconnect(token);
+        }
+    }
+
+    /// Records a failure to establish the session
+    fn connect_ex(&self, token: Token) {
+        CONNECT_EX.increment();
+        if let (Some(breakdown), Some(session)) = (&self.breakdown, self.sessions.get(token.0)) {
+            breakdown.connect_ex(session.endpoint());
+        }
+    }
+
     /// Check if the session is connecting
     fn is_connecting(&self, token: Token) -> Result<bool, Error> {
         let session = get_session!(self, token)?;
@@ -242,21 +464,331 @@ impl Worker {
         Ok(session.timestamp())
     }
 
-    /// Generate and send a request over the session
-    fn send_request(&mut self, token: Token, count: usize) -> Result<(), Error> {
+    /// Write any connection setup commands for a newly connected session. The
+    /// session is added to the ready queue once setup has completed.
+    fn setup(&mut self, token: Token) -> Result<(), Error> {
         let session = get_session_mut!(self, token)?;
-        for _ in 0..count {
+        let count = self.codec.setup(session);
+        session.set_setup(count);
+        if count == 0 {
+            self.ready_queue.push_back(token);
+        } else {
+            self.set_deadline(token, self.request_timeout, Instant::now())?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if requests are taken from work queues rather than
+    /// generated.
+    fn is_queued(&self) -> bool {
+        self.generator.is_queue() || !self.shards.is_empty()
+    }
+
+    /// Returns true if requests are sent to the endpoint for their key.
+    fn is_routed(&self) -> bool {
+        self.cluster.is_some() || self.router.is_some()
+    }
+
+    /// Returns the endpoint for the key of the next request, if known.
+    fn route_next(&mut self) -> Option<SocketAddr> {
+        let key = self.generator.peek()?.request.key()?;
+        if let Some(ref mut cluster) = self.cluster {
+            cluster.route(key)
+        } else {
+            self.router.as_ref().map(|router| router.route(key))
+        }
+    }
+
+    /// Returns a ready session to send the next request over. When requests
+    /// are routed, this is a session to the endpoint for the key of the next
+    /// request. When each session has its own work queue, this is a session
+    /// which has requests in its queue.
+    ///
+    /// A routed request which can not be sent blocks the requests behind it,
+    /// so once requests have waited for longer than the request timeout, or
+    /// `ROUTE_WAIT` if there is none, requests for endpoints without a ready
+    /// session are dropped until one can be sent.
+    fn next_ready(&mut self) -> Option<Token> {
+        if self.is_routed() {
+            let endpoint = self.route_next()?;
+            if let Some(token) = self.take_ready(endpoint) {
+                self.blocked = None;
+                return Some(token);
+            }
+            let now = Instant::now();
+            let since = *self.blocked.get_or_insert(now);
+            if now - since >= self.request_timeout.unwrap_or(ROUTE_WAIT) {
+                self.drop_unrouted();
+            }
+            None
+        } else if !self.shards.is_empty() {
+            self.take_ready_with_work()
+        } else {
+            self.ready_queue.pop_front()
+        }
+    }
+
+    /// Drops the next request, which could not be sent because there is no
+    /// ready session to its endpoint.
+    fn drop_unrouted(&mut self) {
+        if self.generator.generate().is_none() {
+            return;
+        }
+        REQUEST_UNROUTED.increment();
+        if self.open_loop && self.schedule.pop_front().is_some() {
+            self.delayed = self.delayed.saturating_sub(1);
+        }
+    }
+
+    /// Removes the first ready session which has requests in its work queue
+    /// from the ready queue.
+    fn take_ready_with_work(&mut self) -> Option<Token> {
+        for position in 0..self.ready_queue.len() {
+            let token = self.ready_queue[position];
+            let shard = match self.sessions.get(token.0).and_then(|s| s.shard()) {
+                Some(shard) => shard,
+                None => continue,
+            };
+            if self.shards[shard].generator.peek().is_some() {
+                return self.ready_queue.remove(position);
+            }
+        }
+        None
+    }
+
+    /// Removes a ready session to the endpoint from the ready queue.
+    fn take_ready(&mut self, endpoint: SocketAddr) -> Option<Token> {
+        let sessions = &self.sessions;
+        let position = self.ready_queue.iter().position(|token| {
+            sessions
+                .get(token.0)
+                .map(|session| session.endpoint() == Some(endpoint))
+                .unwrap_or(false)
+        })?;
+        self.ready_queue.remove(position)
+    }
+
+    /// Generate and send up to `count` requests over the session, returning
+    /// the number of requests sent. When requests are routed, the pipeline
+    /// ends early if the next request is for a different endpoint. Fewer
+    /// requests are sent if the work queue runs out. Latency is measured from
+    /// `start`, which is the time the requests should have been sent.
+    fn send_request(&mut self, token: Token, count: usize, start: Instant) -> Result<usize, Error> {
+        let now = Instant::now();
+        let deadline = self.request_timeout.map(|timeout| now + timeout);
+        let mut sent = 0;
+        while sent < count {
+            if sent > 0 && self.is_routed() {
+                let endpoint = get_session!(self, token)?.endpoint();
+                if self.route_next() != endpoint {
+                    break;
+                }
+            }
+            let session = get_session_mut!(self, token)?;
+            let generator = match session.shard() {
+                Some(shard) => &mut self.shards[shard].generator,
+                None => &mut self.generator,
+            };
+            let generated = match generator.generate() {
+                Some(generated) => generated,
+                None => break,
+            };
+            let (keys, versions) = match self.versions {
+                Some(ref versions) => versioned(&generated.request, versions),
+                None => (Vec::new(), Vec::new()),
+            };
+            if let Some(ref mut cluster) = self.cluster {
+                let offset = session.write_pending();
+                self.codec.encode(session, &generated.request);
+                cluster.sent(token, &session.write_buffer()[offset..]);
+            } else {
+                self.codec.encode(session, &generated.request);
+            }
+            if let Some(ref mut trace) = self.trace {
+                trace.record(&generated.request, session.endpoint());
+            }
+            let verb = generated.request.verb();
+            if let Verb::Get | Verb::Hget = verb {
+                REQUEST_GET.increment();
+            }
+            let request = InFlight {
+                start,
+                deadline,
+                verb: Some(verb),
+                keyspace: generated.keyspace,
+                keys,
+                versions,
+                asking: false,
+            };
+            if let Some(ref breakdown) = self.breakdown {
+                breakdown.request(&request, session.endpoint());
+            }
+            session.push_request(request);
             REQUEST.increment();
-            self.codec.encode(session);
+            sent += 1;
         }
-        session.set_outstanding(count);
-        session.set_timestamp(Instant::now());
+        if sent == 0 {
+            return Ok(0);
+        }
+        self.set_deadline(token, self.request_timeout, now)?;
+        let session = get_session_mut!(self, token)?;
         let _ = session.flush();
         if session.write_pending() > 0 {
-            self.reregister(token)
-        } else {
-            Ok(())
+            self.reregister(token)?;
+        }
+        Ok(sent)
+    }
+
+    /// Sends requests in open-loop mode. The request ratelimiter is drained
+    /// to find the intended send time of each request and the requests are
+    /// sent over any ready sessions. Requests which can not be sent because
+    /// no session is ready remain queued and are counted as delayed. If too
+    /// many requests are queued, the rest are dropped.
+    fn send_scheduled(&mut self) {
+        if let Some(ref r) = self.request_ratelimit {
+            let now = Instant::now();
+            let mut available = 0;
+            while r.try_wait().is_ok() {
+                available += 1;
+            }
+            // the ratelimiter does not provide the time at which each request
+            // became available, so the requests are spread evenly over the
+            // time since it was last drained
+            let since = self.scheduled.unwrap_or(now);
+            let elapsed = (now - since).as_nanos();
+            for i in 1..=available {
+                if self.schedule.len() >= MAX_SCHEDULED {
+                    REQUEST_DROPPED.add(available - i + 1);
+                    break;
+                }
+                let start = since + Duration::from_nanos(elapsed * i / available);
+                self.schedule.push_back(start);
+            }
+            self.scheduled = Some(now);
+        }
+
+        while let Some(start) = self.schedule.front().copied() {
+            let token = match self.next_ready() {
+                Some(token) => token,
+                None => break,
+            };
+            let reconnect = if let Some(r) = &self.reconnect_ratelimit {
+                r.try_wait().is_ok()
+            } else {
+                false
+            };
+            if reconnect {
+                let _ = self.disconnect(token);
+                continue;
+            }
+            // a pipeline is measured from the oldest request it contains
+            let count = self.pipeline.min(self.schedule.len());
+            match self.send_request(token, count, start) {
+                Ok(0) => {
+                    // the work queue is empty
+                    self.ready_queue.push_front(token);
+                    break;
+                }
+                Ok(sent) => {
+                    self.schedule.drain(..sent);
+                    self.delayed = self.delayed.saturating_sub(sent);
+                }
+                Err(_) => {
+                    let _ = self.disconnect(token);
+                    REQUEST_EX.increment();
+                }
+            }
+        }
+
+        // requests which are still queued have missed their send time
+        let queued = self.schedule.len();
+        if queued > self.delayed {
+            REQUEST_DELAYED.add((queued - self.delayed) as u64);
+        }
+        self.delayed = queued;
+    }
+
+    /// Sends `CLUSTER SLOTS` over a ready session if the cluster topology
+    /// needs to be refreshed.
+    fn refresh_topology(&mut self) -> Result<(), Error> {
+        let cluster = match self.cluster {
+            Some(ref mut cluster) if cluster.needs_refresh() => cluster,
+            _ => return Ok(()),
+        };
+        let token = match self.ready_queue.pop_front() {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+        cluster.start_refresh(token);
+
+        let now = Instant::now();
+        self.set_deadline(token, self.request_timeout, now)?;
+        let session = get_session_mut!(self, token)?;
+        session.write_all(SLOTS_REQUEST)?;
+        session.push_request(InFlight {
+            start: now,
+            deadline: session.deadline(),
+            verb: None,
+            keyspace: None,
+            keys: Vec::new(),
+            versions: Vec::new(),
+            asking: false,
+        });
+        let _ = session.flush();
+        if session.write_pending() > 0 {
+            self.reregister(token)?;
         }
+        Ok(())
+    }
+
+    /// Sends the next redirected request if there is a ready session to the
+    /// node it was redirected to.
+    fn send_retry(&mut self) -> Result<(), Error> {
+        let endpoint = match self.cluster.as_ref().and_then(|c| c.next_retry()) {
+            Some(endpoint) => endpoint,
+            None => return Ok(()),
+        };
+        let token = match self.take_ready(endpoint) {
+            Some(token) => token,
+            None => return Ok(()),
+        };
+        let now = Instant::now();
+        self.set_deadline(token, self.request_timeout, now)?;
+        let cluster = match self.cluster {
+            Some(ref mut cluster) => cluster,
+            None => return Ok(()),
+        };
+        let retry = match cluster.pop_retry() {
+            Some(retry) => retry,
+            None => return Ok(()),
+        };
+
+        let session = get_session_mut!(self, token)?;
+        if retry.asking {
+            session.write_all(ASKING_REQUEST)?;
+        }
+        session.write_all(&retry.request)?;
+        cluster.sent(token, &retry.request);
+        // latency includes the time taken by the redirect
+        let request = retry.inflight.unwrap_or(InFlight {
+            start: now,
+            deadline: None,
+            verb: None,
+            keyspace: None,
+            keys: Vec::new(),
+            versions: Vec::new(),
+            asking: false,
+        });
+        session.push_request(InFlight {
+            deadline: session.deadline(),
+            asking: retry.asking,
+            ..request
+        });
+        let _ = session.flush();
+        if session.write_pending() > 0 {
+            self.reregister(token)?;
+        }
+        Ok(())
     }
 
     /// Handle reading from the session
@@ -269,33 +801,198 @@ impl Worker {
                 Err(Error::new(ErrorKind::Other, "server hangup"))
             }
             Ok(_) => {
+                // connection setup parsing
+                if session.setup() > 0 {
+                    while session.setup() > 0 {
+                        match self.codec.decode_setup(session) {
+                            Ok(()) => {
+                                session.set_setup(session.setup() - 1);
+                            }
+                            Err(ParseError::Incomplete) => {
+                                return Ok(());
+                            }
+                            Err(_) => {
+                                CONNECT_SETUP_EX.increment();
+                                return Err(Error::from(ErrorKind::InvalidData));
+                            }
+                        }
+                    }
+                    if session.outstanding() == 0 {
+                        session.set_deadline(None);
+                        self.ready_queue.push_back(token);
+                        return Ok(());
+                    }
+                }
+
+                // cluster topology parsing
+                if let Some(ref mut cluster) = self.cluster {
+                    if cluster.is_refreshing(token) {
+                        let (ranges, consumed) = match cluster_slots(session.buffer()) {
+                            Ok(slots) => slots,
+                            Err(ParseError::Incomplete) => {
+                                return Ok(());
+                            }
+                            Err(_) => {
+                                warn!("failed to parse the cluster topology");
+                                return Err(Error::from(ErrorKind::InvalidData));
+                            }
+                        };
+                        let endpoint = session
+                            .endpoint()
+                            .ok_or_else(|| Error::from(ErrorKind::NotConnected))?;
+                        session.consume(consumed);
+                        session.pop_request();
+                        session.set_deadline(None);
+                        CLUSTER_REFRESH.increment();
+                        cluster.update(ranges, endpoint);
+                        self.ready_queue.push_back(token);
+                        return Ok(());
+                    }
+                }
+
                 // request parsing
                 while session.outstanding() > 0 {
-                    let response = self.codec.decode(session);
+                    // the reply to `ASKING` precedes the response to a
+                    // request which was redirected by `ASK`
+                    if session.next_request().map(|r| r.asking).unwrap_or(false) {
+                        match self.codec.decode(session) {
+                            Ok(response) => {
+                                if response.kind == ResponseKind::Error {
+                                    RESPONSE_EX.increment();
+                                    if let (Some(request), Some(breakdown)) =
+                                        (session.next_request(), self.breakdown.as_ref())
+                                    {
+                                        breakdown.response_ex(request, session.endpoint());
+                                    }
+                                }
+                                if let Some(request) = session.next_request_mut() {
+                                    request.asking = false;
+                                }
+                                continue;
+                            }
+                            Err(ParseError::Incomplete) => {
+                                return Ok(());
+                            }
+                            Err(_) => {
+                                if let (Some(request), Some(breakdown)) =
+                                    (session.next_request(), self.breakdown.as_ref())
+                                {
+                                    breakdown.response_ex(request, session.endpoint());
+                                }
+                                return Err(Error::from(ErrorKind::InvalidData));
+                            }
+                        }
+                    }
+                    let response = if self.versions.is_some() {
+                        self.codec.decode_items(session)
+                    } else {
+                        self.codec.decode(session).map(|r| (r, Vec::new()))
+                    };
                     match response {
-                        Ok(()) => {
-                            session.set_outstanding(session.outstanding() - 1);
+                        Ok((response, items)) => {
+                            let request = session.pop_request();
                             RESPONSE.increment();
-                            if let Some(ref heatmap) = self.request_heatmap {
+                            RESPONSE_BYTE.add(response.bytes as _);
+                            // replies which only acknowledge a store, such as
+                            // the redis `OK`, or the nil reply to a conditional
+                            // set, are accounted by the verb
+                            let verb = request.as_ref().and_then(|r| r.verb);
+                            let kind = match (response.kind, verb) {
+                                (ResponseKind::Ok, Some(Verb::Set)) => ResponseKind::Stored,
+                                (ResponseKind::Miss, Some(Verb::Set)) => ResponseKind::NotStored,
+                                (kind, _) => kind,
+                            };
+                            match kind {
+                                ResponseKind::Hit => {
+                                    RESPONSE_HIT.add(response.items as _);
+                                    if let (Some(request), Some(breakdown)) =
+                                        (request.as_ref(), self.breakdown.as_ref())
+                                    {
+                                        breakdown.hit(request, session.endpoint());
+                                    }
+                                }
+                                ResponseKind::Miss => {
+                                    RESPONSE_MISS.increment();
+                                    if let (Some(request), Some(breakdown)) =
+                                        (request.as_ref(), self.breakdown.as_ref())
+                                    {
+                                        breakdown.miss(request, session.endpoint());
+                                    }
+                                }
+                                ResponseKind::Stored => {
+                                    RESPONSE_STORED.increment();
+                                }
+                                ResponseKind::NotStored => {
+                                    RESPONSE_NOT_STORED.increment();
+                                }
+                                ResponseKind::Error => {
+                                    RESPONSE_EX.increment();
+                                    if let (Some(request), Some(breakdown)) =
+                                        (request.as_ref(), self.breakdown.as_ref())
+                                    {
+                                        breakdown.response_ex(request, session.endpoint());
+                                    }
+                                }
+                                ResponseKind::Ok => {}
+                            }
+                            if let (Some(request), Some(versions)) =
+                                (request.as_ref(), self.versions.as_ref())
+                            {
+                                verify(request, kind, items, versions);
+                            }
+                            if let Some(request) = request {
                                 let now = Instant::now();
-                                let elapsed = now - session.timestamp();
+                                let elapsed = now - request.start;
                                 let us = elapsed.as_nanos() as u64 / 1_000;
-                                heatmap.increment(now, us, 1);
-                                if let Some(ref waterfall) = self.request_waterfall {
-                                    waterfall.increment(now, elapsed.as_nanos() as u64, 1);
+                                if let Some(ref heatmap) = self.request_heatmap {
+                                    heatmap.increment(now, us, 1);
+                                    if let Some(ref waterfall) = self.request_waterfall {
+                                        waterfall.increment(now, elapsed.as_nanos() as u64, 1);
+                                    }
                                 }
+                                if let Some(ref breakdown) = self.breakdown {
+                                    breakdown.response(&request, session.endpoint(), now, us);
+                                }
+                            }
+                            if let Some(ref mut cluster) = self.cluster {
+                                cluster.received(token);
                             }
                         }
-                        Err(e) => match e {
-                            ParseError::Incomplete => {
+                        Err(e) => match (e, self.cluster.as_mut()) {
+                            (ParseError::Incomplete, _) => {
+                                // the deadline follows the oldest request
+                                // which is still waiting
+                                let deadline = session.next_request().and_then(|r| r.deadline);
+                                if deadline != session.deadline() {
+                                    session.set_deadline(deadline);
+                                    if let Some(deadline) = deadline {
+                                        self.timers.insert(token, deadline);
+                                    }
+                                }
                                 return Ok(());
                             }
+                            (ParseError::Moved { slot, endpoint }, Some(cluster)) => {
+                                CLUSTER_MOVED.increment();
+                                let request = session.pop_request();
+                                cluster.moved(token, slot, endpoint, request);
+                            }
+                            (ParseError::Ask { endpoint, .. }, Some(cluster)) => {
+                                CLUSTER_ASK.increment();
+                                let request = session.pop_request();
+                                cluster.ask(token, endpoint, request);
+                            }
                             _ => {
-                                return Err(Error::from(std::io::ErrorKind::InvalidData));
+                                if let (Some(request), Some(breakdown)) =
+                                    (session.next_request(), self.breakdown.as_ref())
+                                {
+                                    breakdown.response_ex(request, session.endpoint());
+                                }
+                                return Err(Error::from(ErrorKind::InvalidData));
                             }
                         },
                     }
                 }
+                session.set_deadline(None);
                 self.ready_queue.push_back(token);
                 Ok(())
             }
@@ -331,6 +1028,19 @@ impl Worker {
         let mut credits = 0;
 
         loop {
+            if let Some(ref mut trace) = self.trace {
+                trace.tick();
+            }
+
+            if let Some(ref mut cluster) = self.cluster {
+                // open sessions to any newly discovered nodes
+                for node in cluster.take_added() {
+                    for _ in 0..self.poolsize {
+                        self.connect_queue.push_back((node, None));
+                    }
+                }
+            }
+
             if let Some((addr, ssl_session)) = self.connect_queue.pop_front() {
                 let connect = if let Some(r) = &self.connect_ratelimit {
                     r.try_wait().is_ok()
@@ -351,7 +1061,18 @@ impl Worker {
                 }
             }
 
-            if let Some(token) = self.ready_queue.pop_front() {
+            if self.cluster.is_some() {
+                if let Err(e) = self.refresh_topology() {
+                    debug!("failed to request cluster topology: {}", e);
+                }
+                if let Err(e) = self.send_retry() {
+                    debug!("failed to retry redirected request: {}", e);
+                }
+            }
+
+            if self.open_loop {
+                self.send_scheduled();
+            } else if let Some(token) = self.next_ready() {
                 let reconnect = if let Some(r) = &self.reconnect_ratelimit {
                     r.try_wait().is_ok()
                 } else {
@@ -371,10 +1092,15 @@ impl Worker {
                         credits = self.pipeline;
                     };
                     if credits == self.pipeline {
-                        credits = 0;
-                        if self.send_request(token, self.pipeline).is_ok() {
+                        if let Ok(sent) = self.send_request(token, self.pipeline, Instant::now()) {
                             // yay, we sent a request
+                            credits -= sent;
+                            if sent == 0 {
+                                // the work queue is empty
+                                self.ready_queue.push_front(token);
+                            }
                         } else if self.disconnect(token).is_ok() {
+                            credits = 0;
                             REQUEST_EX.increment();
                         } else {
                             panic!("this shouldn't happen");
@@ -385,9 +1111,15 @@ impl Worker {
                 }
             }
 
-            let _ = self
-                .poll
-                .poll(&mut events, Some(std::time::Duration::from_millis(10)));
+            // in open-loop mode, the ratelimiter must be checked often so
+            // that the intended send times are accurate. likewise, requests
+            // from a work queue should be sent soon after they are queued
+            let timeout = if self.open_loop || self.is_queued() {
+                std::time::Duration::from_millis(1)
+            } else {
+                std::time::Duration::from_millis(10)
+            };
+            let _ = self.poll.poll(&mut events, Some(timeout));
 
             for event in &events {
                 let token = event.token();
@@ -395,7 +1127,7 @@ impl Worker {
                 // handle error events first
                 if event.is_error() {
                     if self.is_connecting(token).unwrap() {
-                        CONNECT_EX.increment();
+                        self.connect_ex(token);
                     }
                     // increment_counter!(&Stat::WorkerEventError);
                     let _ = self.disconnect(token);
@@ -406,7 +1138,7 @@ impl Worker {
                 if let Ok(true) = self.is_handshaking(token) {
                     if let Err(e) = self.handshake(token) {
                         if e.kind() != ErrorKind::WouldBlock {
-                            CONNECT_EX.increment();
+                            self.connect_ex(token);
                             let _ = self.disconnect(token);
                         }
                     }
@@ -419,7 +1151,7 @@ impl Worker {
                             // finished handshaking
                         }
                         Err(_) => {
-                            CONNECT_EX.increment();
+                            self.connect_ex(token);
                             let _ = self.disconnect(token);
                             continue;
                         }
@@ -437,6 +1169,7 @@ impl Worker {
                     let handshaking = self.is_handshaking(token).unwrap();
                     if connecting && !handshaking {
                         self.connected(token).unwrap();
+                        let _ = self.set_deadline(token, None, Instant::now());
                         OPEN.increment();
                         SESSION.increment();
                         if let Ok(prev) = self.timestamp(token) {
@@ -447,7 +1180,7 @@ impl Worker {
                                 heatmap.increment(now, us, 1);
                             }
                         }
-                        self.ready_queue.push_back(token);
+                        let _ = self.setup(token);
                     } else if connecting {
                         OPEN.increment();
                     }
@@ -459,6 +1192,8 @@ impl Worker {
 
                 let _ = self.reregister(token);
             }
+
+            self.expire_timeouts();
         }
     }
 }
@@ -547,3 +1282,127 @@ pub fn ssl_connector(config: &Tls) -> Result<Option<SslConnector>, std::io::Erro
 
     Ok(Some(builder.build()))
 }
+
+/// Returns the keys and versions which are kept with the request until its
+/// response is received. This is the version of the value for a write, which
+/// was allocated when the request was generated, a new version for a delete,
+/// or the oldest version of each key which a lookup may return.
+fn versioned(request: &Request, versions: &Versions) -> (Vec<Vec<u8>>, Vec<u64>) {
+    match request {
+        Request::Get { keys } | Request::Gets { keys } => {
+            let oldest = keys.iter().map(|key| versions.oldest(key)).collect();
+            (keys.clone(), oldest)
+        }
+        Request::Set { key, value, .. }
+        | Request::Add { key, value, .. }
+        | Request::Replace { key, value, .. } => match integrity::version(value) {
+            Some(version) => (vec![key.clone()], vec![version]),
+            None => (Vec::new(), Vec::new()),
+        },
+        Request::Delete { key } => {
+            let version = versions.delete(key);
+            (vec![key.clone()], vec![version])
+        }
+        _ => (Vec::new(), Vec::new()),
+    }
+}
+
+/// Records that a write was acknowledged, or checks the values returned by a
+/// lookup and counts those which fail, along with the keys which were missing
+/// after a write of their value was acknowledged. Items without a key are
+/// matched to the keys of the request by position.
+fn verify(request: &InFlight, kind: ResponseKind, items: Vec<Item>, versions: &Versions) {
+    match (request.verb, kind) {
+        (Some(Verb::Set), ResponseKind::Stored)
+        | (Some(Verb::Delete), ResponseKind::Ok | ResponseKind::Miss) => {
+            if let (Some(key), Some(version)) = (request.keys.first(), request.versions.first()) {
+                versions.acknowledge(key, *version);
+            }
+        }
+        (Some(Verb::Get), ResponseKind::Hit | ResponseKind::Miss) => {
+            let mut returned = vec![false; request.keys.len()];
+            for (index, item) in items.into_iter().enumerate() {
+                let value = match item.value {
+                    Some(value) => value,
+                    None => continue,
+                };
+                VERIFY.increment();
+                let index = match item.key {
+                    Some(key) => request.keys.iter().position(|k| *k == key),
+                    None => Some(index),
+                };
+                let index = match index.filter(|i| *i < request.versions.len()) {
+                    Some(index) => index,
+                    // a value for a key which was not requested
+                    None => {
+                        VERIFY_PHANTOM.increment();
+                        continue;
+                    }
+                };
+                returned[index] = true;
+                let (key, oldest) = (&request.keys[index], request.versions[index]);
+                match integrity::check(key, &value) {
+                    Check::Valid(version) => match versions.freshness(key, version, oldest) {
+                        Freshness::Current => {}
+                        Freshness::Stale => {
+                            VERIFY_STALE.increment();
+                        }
+                        Freshness::Phantom => {
+                            VERIFY_PHANTOM.increment();
+                        }
+                    },
+                    Check::Truncated => {
+                        VERIFY_TRUNCATED.increment();
+                    }
+                    Check::Corrupt => {
+                        VERIFY_CORRUPT.increment();
+                    }
+                }
+            }
+            // the keys which missed may have lost a write
+            for (index, key) in request.keys.iter().enumerate() {
+                if !returned[index] && versions.lost(key, request.versions[index]) {
+                    VERIFY_LOST.increment();
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    // requests from session queues are sent as they are queued, even when
+    // routing is configured, as they are by `rpc-replay --sharded`
+    #[test]
+    fn session_queues() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = listener.local_addr().unwrap();
+        let config = format!(
+            "[general]\nprotocol = \"memcache\"\nservice = true\n\
+            [target]\nendpoints = [\"{}\"]\nrouting = \"ketama\"\n",
+            endpoint
+        );
+        let config = Config::from_toml(toml::from_str(&config).unwrap());
+        let mut worker = Worker::new(Arc::new(config), 0).unwrap();
+
+        let queue = Queue::with_capacity(16);
+        let _ = queue.push(Request::Get {
+            keys: vec![b"abc".to_vec()],
+        });
+        worker.set_session_queues(vec![(endpoint, queue)]);
+        std::thread::spawn(move || worker.run());
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0; 9];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"get abc\r\n");
+    }
+}